@@ -0,0 +1,142 @@
+//! - Collect the signatures of all `#[no_mangle]` exports for the ABI description (see `src/abi.rs`)
+//! - Record the drift program tag and rustc version for `ffi_version_info`
+use std::{env, fs, path::Path, process::Command};
+
+use quote::ToTokens;
+use syn::{visit_mut::VisitMut, Expr, FnArg, Item, Lit, Meta, Pat, ReturnType, Type};
+
+const EXPORTS_PATH: &str = "src/exports.rs";
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    emit_build_info();
+    generate_exports_abi();
+}
+
+fn emit_build_info() {
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    let manifest = fs::read_to_string("Cargo.toml").expect("read Cargo.toml");
+    let program_tag = manifest
+        .lines()
+        .find(|line| line.starts_with("drift-program"))
+        .and_then(|line| line.split("tag = \"").nth(1))
+        .and_then(|tag| tag.split('"').next())
+        .expect("drift-program tag in Cargo.toml");
+    println!("cargo:rustc-env=DRIFT_PROGRAM_TAG={program_tag}");
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|version| version.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=DRIFT_FFI_RUSTC_VERSION={rustc_version}");
+}
+
+fn generate_exports_abi() {
+    println!("cargo:rerun-if-changed={EXPORTS_PATH}");
+
+    let source = fs::read_to_string(EXPORTS_PATH).expect("read exports");
+    let file = syn::parse_file(&source).expect("parse exports");
+
+    let mut functions = String::new();
+    for item in &file.items {
+        let Item::Fn(item_fn) = item else {
+            continue;
+        };
+        let is_exported = item_fn.attrs.iter().any(|a| a.path().is_ident("no_mangle"))
+            && item_fn
+                .sig
+                .abi
+                .as_ref()
+                .is_some_and(|abi| abi.name.as_ref().is_some_and(|name| name.value() == "C"));
+        if !is_exported {
+            continue;
+        }
+
+        let doc = item_fn
+            .attrs
+            .iter()
+            .filter_map(|a| match &a.meta {
+                Meta::NameValue(nv) if nv.path.is_ident("doc") => match &nv.value {
+                    Expr::Lit(lit) => match &lit.lit {
+                        Lit::Str(s) => Some(s.value().trim().to_string()),
+                        _ => None,
+                    },
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let mut args = String::new();
+        for input in &item_fn.sig.inputs {
+            let FnArg::Typed(pat_type) = input else {
+                panic!("unexpected receiver on export: {}", item_fn.sig.ident);
+            };
+            let Pat::Ident(pat_ident) = &*pat_type.pat else {
+                panic!(
+                    "unsupported argument pattern on export: {}",
+                    item_fn.sig.ident
+                );
+            };
+            args.push_str(&format!(
+                "crate::abi::ArgAbi::new::<{}>({:?}, {:?}),",
+                static_type(&pat_type.ty),
+                pat_ident.ident.to_string(),
+                display_type(&pat_type.ty),
+            ));
+        }
+
+        let (ret_type, ret_display) = match &item_fn.sig.output {
+            ReturnType::Default => ("()".to_string(), "()".to_string()),
+            ReturnType::Type(_, ty) => (static_type(ty), display_type(ty)),
+        };
+
+        functions.push_str(&format!(
+            "crate::abi::FunctionAbi::new::<{ret_type}>({:?}, {doc:?}, {ret_display:?}, vec![{args}]),",
+            item_fn.sig.ident.to_string(),
+        ));
+    }
+
+    let generated = format!(
+        "// @generated by build.rs from {EXPORTS_PATH}\n\
+        /// Signatures of all exported functions\n\
+        pub(crate) fn exported_functions() -> Vec<crate::abi::FunctionAbi> {{ vec![{functions}] }}\n"
+    );
+    let out_path = Path::new(&env::var("OUT_DIR").expect("OUT_DIR set")).join("exports_abi.rs");
+    fs::write(out_path, generated).expect("write exports_abi.rs");
+}
+
+/// Replaces named lifetimes with `'static` so the type can be named outside of the export
+fn static_type(ty: &Type) -> String {
+    struct StaticLifetimes;
+    impl VisitMut for StaticLifetimes {
+        fn visit_lifetime_mut(&mut self, lifetime: &mut syn::Lifetime) {
+            *lifetime = syn::Lifetime::new("'static", lifetime.apostrophe);
+        }
+    }
+    let mut ty = ty.clone();
+    StaticLifetimes.visit_type_mut(&mut ty);
+    ty.to_token_stream().to_string()
+}
+
+/// Rust type as written in the export, without token spacing
+fn display_type(ty: &Type) -> String {
+    ty.to_token_stream()
+        .to_string()
+        .replace(" :: ", "::")
+        .replace("* const ", "*const ")
+        .replace("* mut ", "*mut ")
+        .replace("& '", "&'")
+        .replace("& ", "&")
+        .replace(" <", "<")
+        .replace("< ", "<")
+        .replace(" >", ">")
+        .replace(" ,", ",")
+        .replace("( ", "(")
+        .replace(" )", ")")
+}
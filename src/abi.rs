@@ -0,0 +1,1200 @@
+//! C ABI description of the library exports
+//!
+//! Generates the `drift_ffi.h` header and `drift_ffi_abi.json` manifest shipped with each release.
+//! Layouts are measured from the current build and the header asserts them with `_Static_assert`,
+//! so a C compiler rejects the header if its view of any type disagrees with the library.
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    fs, io,
+    mem::{align_of, size_of, MaybeUninit},
+    path::Path,
+    ptr::addr_of,
+    sync::OnceLock,
+};
+
+use abi_stable::std_types::{ROption, RResult, RSlice, RSliceMut, RStr};
+use anchor_lang::prelude::AccountInfo;
+use drift_program::{
+    controller::position::PositionDirection,
+    math::{margin::MarginRequirementType, oracle::OracleValidity},
+    state::{
+        high_leverage_mode_config::HighLeverageModeConfig,
+        insurance_fund_stake::InsuranceFundStake,
+        oracle::{OraclePriceData, OracleSource},
+        order_params::PostOnlyParam,
+        perp_market::{ContractType, PerpMarket, AMM},
+        protected_maker_mode_config::ProtectedMakerParams,
+        revenue_share::RevenueShareOrder,
+        spot_market::{SpotBalanceType, SpotMarket},
+        state::{FeeTier, OracleGuardRails, State, ValidityGuardRails},
+        user::{
+            MarketType, Order, OrderTriggerCondition, OrderType, PerpPosition, SpotPosition, User,
+            UserStats,
+        },
+    },
+};
+use solana_sdk::{account::Account, clock::Clock, pubkey::Pubkey};
+
+use crate::{
+    context::AccountsContext,
+    margin::{
+        IncrementalMarginCalculation, MarginBreakdown, PositionCollateral, PositionMarginBreakdown,
+    },
+    types::{
+        compat, AccountRiskMetrics, AccountWithKey, AccountsList, FfiFieldOffset, FfiTypeLayout,
+        FfiVersionInfo, IsolatedMarginCalculation, MMOraclePriceData, MarginCalculation,
+        MarginContextMode, MarketState, MaxWithdrawAmount, OrderParams, PlacePerpOrderResult,
+        PriceShock, RiskMetrics, SimplifiedMarginCalculation,
+    },
+};
+
+/// File name of the generated C header
+pub const HEADER_FILE_NAME: &str = "drift_ffi.h";
+/// File name of the generated ABI manifest
+pub const MANIFEST_FILE_NAME: &str = "drift_ffi_abi.json";
+
+// the compat types exist to pin these, fail the build rather than ship a header that disagrees
+const _: () = assert!(size_of::<compat::i128>() == 16 && align_of::<compat::i128>() == 16);
+const _: () = assert!(size_of::<compat::u128>() == 16 && align_of::<compat::u128>() == 16);
+
+/// Offset of `$field` in `$ty` (`std::mem::offset_of` is not available on the pinned toolchain)
+macro_rules! offset_of {
+    ($ty:ty, $field:ident) => {{
+        let uninit = MaybeUninit::<$ty>::uninit();
+        let base = uninit.as_ptr();
+        // SAFETY: only the field address is taken, the uninitialized value is never read
+        let field = unsafe { addr_of!((*base).$field) };
+        field as usize - base as usize
+    }};
+}
+
+/// Layout of `$ty` with offsets of the listed fields
+macro_rules! type_layout {
+    ($name:literal, $ty:ty { $($field:ident),* $(,)? }) => {{
+        let fields: Vec<FfiFieldOffset> = vec![$(FfiFieldOffset {
+            name: RStr::from_str(stringify!($field)),
+            offset: offset_of!($ty, $field),
+        }),*];
+        FfiTypeLayout {
+            name: RStr::from_str($name),
+            size: size_of::<$ty>(),
+            align: align_of::<$ty>(),
+            fields: RSlice::from_slice(Vec::leak(fields)),
+        }
+    }};
+}
+
+/// Layouts of on-chain account/position types and the compat integers as compiled into this library
+///
+/// Callers compare these against their own definitions to detect a build with mismatched layouts
+/// e.g. one compiled with a rustc that changed `i128` alignment
+pub fn type_layouts() -> &'static [FfiTypeLayout] {
+    static LAYOUTS: OnceLock<Vec<FfiTypeLayout>> = OnceLock::new();
+    LAYOUTS.get_or_init(|| {
+        vec![
+            type_layout!(
+                "User",
+                User {
+                    authority,
+                    spot_positions,
+                    perp_positions,
+                    orders,
+                    max_margin_ratio,
+                    status,
+                    pool_id,
+                }
+            ),
+            type_layout!(
+                "PerpMarket",
+                PerpMarket {
+                    amm,
+                    market_index,
+                    margin_ratio_initial,
+                    margin_ratio_maintenance,
+                    contract_tier,
+                    status,
+                    quote_spot_market_index,
+                }
+            ),
+            type_layout!(
+                "SpotMarket",
+                SpotMarket {
+                    oracle,
+                    historical_oracle_data,
+                    cumulative_deposit_interest,
+                    cumulative_borrow_interest,
+                    deposit_balance,
+                    borrow_balance,
+                    initial_asset_weight,
+                    decimals,
+                    market_index,
+                }
+            ),
+            type_layout!(
+                "State",
+                State {
+                    admin,
+                    oracle_guard_rails,
+                    perp_fee_structure,
+                    spot_fee_structure,
+                }
+            ),
+            type_layout!(
+                "Order",
+                Order {
+                    slot,
+                    price,
+                    base_asset_amount,
+                    base_asset_amount_filled,
+                    order_id,
+                    market_index,
+                    status,
+                    order_type,
+                    direction,
+                }
+            ),
+            type_layout!(
+                "PerpPosition",
+                PerpPosition {
+                    last_cumulative_funding_rate,
+                    base_asset_amount,
+                    quote_asset_amount,
+                    open_bids,
+                    open_asks,
+                    market_index,
+                    open_orders,
+                    max_margin_ratio,
+                    isolated_position_scaled_balance,
+                }
+            ),
+            type_layout!(
+                "SpotPosition",
+                SpotPosition {
+                    scaled_balance,
+                    open_bids,
+                    open_asks,
+                    cumulative_deposits,
+                    market_index,
+                    balance_type,
+                    open_orders,
+                }
+            ),
+            type_layout!(
+                "OraclePriceData",
+                OraclePriceData {
+                    price,
+                    confidence,
+                    delay,
+                    has_sufficient_number_of_data_points,
+                    sequence_id,
+                }
+            ),
+            type_layout!("compat::i128", compat::i128 {}),
+            type_layout!("compat::u128", compat::u128 {}),
+        ]
+    })
+}
+
+/// Write the C header and ABI manifest into `dir`
+pub fn write_artifacts(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(HEADER_FILE_NAME), c_header())?;
+    fs::write(dir.join(MANIFEST_FILE_NAME), abi_manifest())
+}
+
+/// C header declaring all exported functions and the types they use
+pub fn c_header() -> String {
+    let functions = crate::exports::exported_functions();
+    let header = Header::from_functions(&functions);
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "/* {HEADER_FILE_NAME}: generated by drift-ffi-abi for drift-ffi-sys v{}, do not edit */",
+        env!("CARGO_PKG_VERSION")
+    );
+    out.push_str(HEADER_PRELUDE);
+    let _ = writeln!(
+        out,
+        "\n/* compare with `ffi_version_info().layout_fingerprint` at load time */\n#define DRIFT_FFI_LAYOUT_FINGERPRINT {:#018x}ULL",
+        layout_fingerprint()
+    );
+    for definition in &header.definitions {
+        out.push('\n');
+        out.push_str(definition);
+    }
+    for function in &functions {
+        out.push('\n');
+        if !function.doc.is_empty() {
+            let _ = writeln!(out, "/* {} */", function.doc.replace("*/", "* /"));
+        }
+        let args = if function.args.is_empty() {
+            "void".to_string()
+        } else {
+            function
+                .args
+                .iter()
+                .map(|a| a.c_decl.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let _ = writeln!(out, "{} {}({args});", function.ret.c_type, function.name);
+    }
+    out.push_str(HEADER_EPILOGUE);
+    out
+}
+
+/// JSON manifest of all exported function signatures, header type layouts and `type_layouts`
+pub fn abi_manifest() -> String {
+    let functions = crate::exports::exported_functions();
+    let header = Header::from_functions(&functions);
+
+    format!(
+        "{{\n  \"crate_version\": {},\n  \"layout_fingerprint\": \"{:#018x}\",\n  \"functions\": [\n    {}\n  ],\n  \"types\": [\n    {}\n  ],\n  \"layouts\": [\n    {}\n  ]\n}}\n",
+        json_str(env!("CARGO_PKG_VERSION")),
+        layout_fingerprint(),
+        functions_json(&functions, true),
+        types_json(&header),
+        layouts_json(),
+    )
+}
+
+/// Fingerprint of the exported ABI: function signatures, header type layouts and `type_layouts`
+///
+/// Changes whenever any signature or layout changes, independent of the crate version
+pub fn layout_fingerprint() -> u64 {
+    static FINGERPRINT: OnceLock<u64> = OnceLock::new();
+    *FINGERPRINT.get_or_init(|| {
+        let functions = crate::exports::exported_functions();
+        let header = Header::from_functions(&functions);
+        fxhash::hash64(&(
+            functions_json(&functions, false),
+            types_json(&header),
+            layouts_json(),
+        ))
+    })
+}
+
+fn functions_json(functions: &[FunctionAbi], with_doc: bool) -> String {
+    functions
+        .iter()
+        .map(|f| {
+            let args = f
+                .args
+                .iter()
+                .map(|a| {
+                    format!(
+                        r#"{{"name":{},"rust":{},"c":{}}}"#,
+                        json_str(a.name),
+                        json_str(a.rust_type),
+                        json_str(&a.c_type)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            let doc = if with_doc {
+                format!(r#""doc":{},"#, json_str(f.doc))
+            } else {
+                String::new()
+            };
+            format!(
+                r#"{{"name":{},{doc}"args":[{args}],"return":{{"rust":{},"c":{}}}}}"#,
+                json_str(f.name),
+                json_str(f.ret.rust_type),
+                json_str(&f.ret.c_type),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",\n    ")
+}
+
+fn types_json(header: &Header) -> String {
+    header
+        .layouts
+        .iter()
+        .map(|t| {
+            let fields = t
+                .fields
+                .iter()
+                .map(|f| {
+                    format!(
+                        r#"{{"name":{},"c":{},"offset":{},"size":{}}}"#,
+                        json_str(f.name),
+                        json_str(&f.c_type),
+                        f.offset,
+                        f.size
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            format!(
+                r#"{{"name":{},"size":{},"align":{},"fields":[{fields}]}}"#,
+                json_str(&t.name),
+                t.size,
+                t.align
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",\n    ")
+}
+
+fn layouts_json() -> String {
+    type_layouts()
+        .iter()
+        .map(|t| {
+            let fields = t
+                .fields
+                .iter()
+                .map(|f| format!(r#"{{"name":{},"offset":{}}}"#, json_str(&f.name), f.offset))
+                .collect::<Vec<_>>()
+                .join(",");
+            format!(
+                r#"{{"name":{},"size":{},"align":{},"fields":[{fields}]}}"#,
+                json_str(&t.name),
+                t.size,
+                t.align
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",\n    ")
+}
+
+const HEADER_PRELUDE: &str = r#"
+#ifndef DRIFT_FFI_H
+#define DRIFT_FFI_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+#define DRIFT_FFI_ALIGNAS(n) alignas(n)
+#define DRIFT_FFI_ALIGNOF(t) alignof(t)
+#define DRIFT_FFI_STATIC_ASSERT(c, m) static_assert(c, m)
+extern "C" {
+#else
+#define DRIFT_FFI_ALIGNAS(n) _Alignas(n)
+#define DRIFT_FFI_ALIGNOF(t) _Alignof(t)
+#define DRIFT_FFI_STATIC_ASSERT(c, m) _Static_assert(c, m)
+#endif
+
+/*
+ * Types with an `opaque` member have Rust layout, only their size and alignment are part of the ABI.
+ * `RResult_*`/`ROption_*` are `abi_stable` enums: `tag` 0 is `ok`/`some`.
+ */
+"#;
+
+const HEADER_EPILOGUE: &str = r#"
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* DRIFT_FFI_H */
+"#;
+
+/// Signature of an exported function
+pub(crate) struct FunctionAbi {
+    name: &'static str,
+    doc: &'static str,
+    ret: ArgAbi,
+    args: Vec<ArgAbi>,
+}
+
+impl FunctionAbi {
+    pub(crate) fn new<R: CType>(
+        name: &'static str,
+        doc: &'static str,
+        ret_rust_type: &'static str,
+        args: Vec<ArgAbi>,
+    ) -> Self {
+        Self {
+            name,
+            doc,
+            ret: ArgAbi::new::<R>("", ret_rust_type),
+            args,
+        }
+    }
+}
+
+/// Argument (or return value) of an exported function
+pub(crate) struct ArgAbi {
+    name: &'static str,
+    rust_type: &'static str,
+    c_type: String,
+    c_decl: String,
+    define: fn(&mut Header),
+}
+
+impl ArgAbi {
+    pub(crate) fn new<T: CType>(name: &'static str, rust_type: &'static str) -> Self {
+        Self {
+            name,
+            rust_type,
+            c_type: T::c_name(),
+            c_decl: T::c_decl(c_param_name(name)),
+            define: T::define,
+        }
+    }
+}
+
+/// `name` as a C parameter name, avoiding C++ keywords
+fn c_param_name(name: &str) -> &str {
+    match name {
+        "this" => "self_",
+        "new" => "new_",
+        "delete" => "delete_",
+        _ => name,
+    }
+}
+
+/// Layout of a type defined in the header
+#[derive(Clone, Debug)]
+pub(crate) struct TypeLayout {
+    pub name: String,
+    pub size: usize,
+    pub align: usize,
+    /// declared fields, empty for opaque types
+    pub fields: Vec<FieldLayout>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct FieldLayout {
+    pub name: &'static str,
+    pub c_type: String,
+    c_decl: String,
+    pub offset: usize,
+    pub size: usize,
+    align: usize,
+}
+
+impl FieldLayout {
+    /// Describe the field `name` at `offset` as C type `C`, `field` points at the Rust field
+    fn new<C: CType, F>(
+        header: &mut Header,
+        name: &'static str,
+        offset: usize,
+        _field: *const F,
+    ) -> Self {
+        assert!(
+            size_of::<C>() == size_of::<F>() && align_of::<C>() == align_of::<F>(),
+            "C type of field `{name}` does not match its Rust layout"
+        );
+        C::define(header);
+        Self {
+            name,
+            c_type: C::c_name(),
+            c_decl: C::c_decl(name),
+            offset,
+            size: size_of::<F>(),
+            align: align_of::<F>(),
+        }
+    }
+}
+
+/// Type definitions of the C header, in dependency order
+#[derive(Default)]
+pub(crate) struct Header {
+    seen: HashSet<String>,
+    definitions: Vec<String>,
+    layouts: Vec<TypeLayout>,
+}
+
+impl Header {
+    fn from_functions(functions: &[FunctionAbi]) -> Self {
+        let mut header = Self::default();
+        for function in functions {
+            (function.ret.define)(&mut header);
+            for arg in &function.args {
+                (arg.define)(&mut header);
+            }
+        }
+        header
+    }
+
+    /// Returns true the first time type `name` is seen
+    fn begin(&mut self, name: &str) -> bool {
+        self.seen.insert(name.to_string())
+    }
+
+    /// Forward declare a type that only crosses the boundary behind a pointer
+    fn handle(&mut self, name: &str) {
+        if self.begin(name) {
+            self.definitions
+                .push(format!("typedef struct {name} {name};\n"));
+        }
+    }
+
+    /// Define `T` as a correctly sized and aligned blob
+    fn opaque<T>(&mut self, name: &str) {
+        if !self.begin(name) {
+            return;
+        }
+        let layout = TypeLayout {
+            name: name.to_string(),
+            size: size_of::<T>(),
+            align: align_of::<T>(),
+            fields: vec![],
+        };
+        self.push(
+            format!(
+                "typedef struct {name} {{\n    DRIFT_FFI_ALIGNAS({}) uint8_t opaque[{}];\n}} {name};\n",
+                layout.align, layout.size
+            ),
+            layout,
+        );
+    }
+
+    /// Define `T` as a struct of `fields`
+    ///
+    /// Fields are emitted in offset order with explicit padding, so Rust layout structs are described too
+    fn structure<T>(&mut self, name: &str, mut fields: Vec<FieldLayout>) {
+        let size = size_of::<T>();
+        let align = align_of::<T>();
+        fields.sort_by_key(|f| f.offset);
+
+        let mut body = String::new();
+        let mut cursor = 0_usize;
+        for (i, field) in fields.iter().enumerate() {
+            if field.offset > cursor.next_multiple_of(field.align) {
+                let _ = writeln!(body, "    uint8_t _pad{i}[{}];", field.offset - cursor);
+            }
+            // the first member carries the struct alignment e.g. `repr(align(16))`
+            let alignas = if i == 0 {
+                format!("DRIFT_FFI_ALIGNAS({align}) ")
+            } else {
+                String::new()
+            };
+            let _ = writeln!(body, "    {alignas}{};", field.c_decl);
+            cursor = field.offset + field.size;
+        }
+        if size > cursor.next_multiple_of(align) {
+            let _ = writeln!(body, "    uint8_t _pad{}[{}];", fields.len(), size - cursor);
+        }
+
+        self.push(
+            format!("typedef struct {name} {{\n{body}}} {name};\n"),
+            TypeLayout {
+                name: name.to_string(),
+                size,
+                align,
+                fields,
+            },
+        );
+    }
+
+    /// Define `T`, a `repr(u8)` enum, as a union of its variants, each led by the `u8` tag
+    fn tagged_union<T>(&mut self, name: &str, variants: &[(&str, Option<String>)]) {
+        let mut body = String::from("    uint8_t tag;\n");
+        for (variant, value) in variants {
+            match value {
+                Some(value_decl) => {
+                    let _ = writeln!(
+                        body,
+                        "    struct {{ uint8_t tag; {value_decl}; }} {variant};"
+                    );
+                }
+                None => {
+                    let _ = writeln!(body, "    struct {{ uint8_t tag; }} {variant};");
+                }
+            }
+        }
+        self.push(
+            format!("typedef union {name} {{\n{body}}} {name};\n"),
+            TypeLayout {
+                name: name.to_string(),
+                size: size_of::<T>(),
+                align: align_of::<T>(),
+                fields: vec![],
+            },
+        );
+    }
+
+    fn push(&mut self, mut definition: String, layout: TypeLayout) {
+        let name = &layout.name;
+        let _ = writeln!(
+            definition,
+            "DRIFT_FFI_STATIC_ASSERT(sizeof({name}) == {}, \"{name} size\");",
+            layout.size
+        );
+        let _ = writeln!(
+            definition,
+            "DRIFT_FFI_STATIC_ASSERT(DRIFT_FFI_ALIGNOF({name}) == {}, \"{name} align\");",
+            layout.align
+        );
+        for field in &layout.fields {
+            let _ = writeln!(
+                definition,
+                "DRIFT_FFI_STATIC_ASSERT(offsetof({name}, {}) == {}, \"{name}.{} offset\");",
+                field.name, field.offset, field.name
+            );
+        }
+        self.definitions.push(definition);
+        self.layouts.push(layout);
+    }
+}
+
+/// A type that crosses the FFI boundary
+pub(crate) trait CType {
+    /// True for non-null pointers, `Option<Self>` is then a nullable pointer
+    const IS_POINTER: bool = false;
+
+    /// The type name in C
+    fn c_name() -> String;
+
+    /// C declaration of a field or parameter `name` of this type
+    fn c_decl(name: &str) -> String {
+        format!("{} {name}", Self::c_name())
+    }
+
+    /// Add the definitions of this type, and those it depends on, to `header`
+    fn define(_header: &mut Header) {}
+}
+
+/// `c_name` of `T` usable as part of an identifier
+fn ident<T: CType>() -> String {
+    T::c_name()
+        .replace(" const *", "_cptr")
+        .replace(" *", "_ptr")
+        .replace(['[', ']', ' '], "_")
+}
+
+impl CType for () {
+    fn c_name() -> String {
+        "void".to_string()
+    }
+}
+
+macro_rules! primitive_types {
+    ($($ty:ty => $name:literal),* $(,)?) => {$(
+        impl CType for $ty {
+            fn c_name() -> String {
+                $name.to_string()
+            }
+        }
+    )*};
+}
+
+primitive_types!(
+    bool => "bool",
+    u8 => "uint8_t",
+    u16 => "uint16_t",
+    u32 => "uint32_t",
+    u64 => "uint64_t",
+    usize => "uintptr_t",
+    i8 => "int8_t",
+    i16 => "int16_t",
+    i32 => "int32_t",
+    i64 => "int64_t",
+    isize => "intptr_t",
+);
+
+macro_rules! opaque_types {
+    ($($ty:ty => $name:literal),* $(,)?) => {$(
+        impl CType for $ty {
+            fn c_name() -> String {
+                $name.to_string()
+            }
+            fn define(header: &mut Header) {
+                header.opaque::<Self>($name);
+            }
+        }
+    )*};
+}
+
+opaque_types!(
+    // native 128-bit integers, alignment depends on the rustc version
+    i128 => "rust_i128",
+    u128 => "rust_u128",
+    compat::i128 => "compat_i128",
+    compat::u128 => "compat_u128",
+    String => "RustString",
+    Pubkey => "Pubkey",
+    Account => "Account",
+    AccountInfo<'_> => "AccountInfo",
+    User => "User",
+    UserStats => "UserStats",
+    InsuranceFundStake => "InsuranceFundStake",
+    HighLeverageModeConfig => "HighLeverageModeConfig",
+    State => "State",
+    PerpMarket => "PerpMarket",
+    SpotMarket => "SpotMarket",
+    AMM => "AMM",
+    Order => "Order",
+    PerpPosition => "PerpPosition",
+    SpotPosition => "SpotPosition",
+    FeeTier => "FeeTier",
+    ValidityGuardRails => "ValidityGuardRails",
+    OracleGuardRails => "OracleGuardRails",
+    ProtectedMakerParams => "ProtectedMakerParams",
+    RevenueShareOrder => "RevenueShareOrder",
+    OracleSource => "OracleSource",
+    OracleValidity => "OracleValidity",
+    MarginRequirementType => "MarginRequirementType",
+    MarginContextMode => "MarginContextMode",
+    PositionDirection => "PositionDirection",
+    ContractType => "ContractType",
+    SpotBalanceType => "SpotBalanceType",
+    MarketType => "MarketType",
+    OrderType => "OrderType",
+    PostOnlyParam => "PostOnlyParam",
+    OrderTriggerCondition => "OrderTriggerCondition",
+);
+
+/// Library owned types passed by pointer only, forward declared in C
+macro_rules! handle_types {
+    ($($ty:ty => $name:literal),* $(,)?) => {
+        $(
+            impl CType for $ty {
+                fn c_name() -> String {
+                    $name.to_string()
+                }
+                fn define(header: &mut Header) {
+                    header.handle($name);
+                }
+            }
+        )*
+    };
+}
+
+handle_types!(
+    MarketState => "MarketState",
+    AccountsContext => "AccountsContext",
+);
+
+/// Measure the field `$field` of `$ty` and describe it as C type `$c_ty`
+macro_rules! field {
+    ($header:expr, $ty:ty, $field:ident: $c_ty:ty) => {{
+        let uninit = MaybeUninit::<$ty>::uninit();
+        let base = uninit.as_ptr();
+        // SAFETY: only the field address is taken, the uninitialized value is never read
+        let field = unsafe { addr_of!((*base).$field) };
+        FieldLayout::new::<$c_ty, _>(
+            $header,
+            stringify!($field),
+            field as usize - base as usize,
+            field,
+        )
+    }};
+}
+
+/// Describe a struct field by field, `$c_ty` is the C view of each field
+macro_rules! struct_types {
+    ($($ty:ty => $name:literal { $($field:ident: $c_ty:ty),* $(,)? }),* $(,)?) => {$(
+        impl CType for $ty {
+            fn c_name() -> String {
+                $name.to_string()
+            }
+            fn define(header: &mut Header) {
+                if header.begin($name) {
+                    let fields = vec![$(field!(header, $ty, $field: $c_ty)),*];
+                    header.structure::<Self>($name, fields);
+                }
+            }
+        }
+    )*};
+}
+
+struct_types!(
+    OraclePriceData => "OraclePriceData" {
+        price: i64,
+        confidence: u64,
+        delay: i64,
+        has_sufficient_number_of_data_points: bool,
+        sequence_id: Option<u64>,
+    },
+    MMOraclePriceData => "MMOraclePriceData" {
+        mm_oracle_price: i64,
+        mm_oracle_delay: i64,
+        mm_oracle_validity: OracleValidity,
+        mm_exchange_diff_bps: compat::u128,
+        exchange_oracle_price_data: OraclePriceData,
+        safe_oracle_price_data: OraclePriceData,
+    },
+    Clock => "Clock" {
+        slot: u64,
+        epoch_start_timestamp: i64,
+        epoch: u64,
+        leader_schedule_epoch: u64,
+        unix_timestamp: i64,
+    },
+    AccountWithKey => "AccountWithKey" {
+        key: Pubkey,
+        account: Account,
+    },
+    AccountsList<'_> => "AccountsList" {
+        perp_markets: MutSlice<AccountWithKey>,
+        spot_markets: MutSlice<AccountWithKey>,
+        oracles: MutSlice<AccountWithKey>,
+        oracle_guard_rails: Option<OracleGuardRails>,
+        latest_slot: u64,
+    },
+    PlacePerpOrderResult => "PlacePerpOrderResult" {
+        user: User,
+        order_index: usize,
+        order: Order,
+    },
+    MaxWithdrawAmount => "MaxWithdrawAmount" {
+        withdraw: u64,
+        withdraw_with_borrow: u64,
+    },
+    RiskMetrics => "RiskMetrics" {
+        total_collateral: compat::i128,
+        margin_requirement: compat::u128,
+        distance_to_liquidation: compat::i128,
+        total_notional: compat::u128,
+        leverage: compat::u128,
+        margin_ratio: compat::u128,
+        health: u8,
+    },
+    AccountRiskMetrics => "AccountRiskMetrics" {
+        cross: RiskMetrics,
+        isolated: [RiskMetrics; 8],
+    },
+    PriceShock => "PriceShock" {
+        market_type: MarketType,
+        market_index: u16,
+        shock: i64,
+    },
+    OrderParams => "OrderParams" {
+        order_type: OrderType,
+        market_type: MarketType,
+        direction: PositionDirection,
+        user_order_id: u8,
+        base_asset_amount: u64,
+        price: u64,
+        market_index: u16,
+        reduce_only: bool,
+        post_only: PostOnlyParam,
+        bit_flags: u8,
+        max_ts: Option<i64>,
+        trigger_price: Option<u64>,
+        trigger_condition: OrderTriggerCondition,
+        oracle_price_offset: Option<i32>,
+        auction_duration: Option<u8>,
+        auction_start_price: Option<i64>,
+        auction_end_price: Option<i64>,
+    },
+    IsolatedMarginCalculation => "IsolatedMarginCalculation" {
+        margin_requirement: compat::u128,
+        total_collateral: compat::i128,
+        total_collateral_buffer: compat::i128,
+        margin_requirement_plus_buffer: compat::u128,
+        market_index: u16,
+    },
+    MarginCalculation => "MarginCalculation" {
+        total_collateral: compat::i128,
+        margin_requirement: compat::u128,
+        with_perp_isolated_liability: bool,
+        with_spot_isolated_liability: bool,
+        total_spot_asset_value: compat::i128,
+        total_spot_liability_value: compat::u128,
+        total_perp_liability_value: compat::u128,
+        total_perp_pnl: compat::i128,
+        isolated_margin_calculations: [IsolatedMarginCalculation; 8],
+    },
+    SimplifiedMarginCalculation => "SimplifiedMarginCalculation" {
+        total_collateral: compat::i128,
+        total_collateral_buffer: compat::i128,
+        margin_requirement: compat::u128,
+        margin_requirement_plus_buffer: compat::u128,
+        isolated_margin_calculations: [IsolatedMarginCalculation; 8],
+        with_perp_isolated_liability: bool,
+        with_spot_isolated_liability: bool,
+    },
+    PositionCollateral => "PositionCollateral" {
+        collateral_value: i128,
+        collateral_buffer: i128,
+        liability_value: u128,
+        liability_buffer: u128,
+        last_updated: u64,
+        market_index: u16,
+        is_isolated: bool,
+        has_isolated_liability: bool,
+    },
+    PositionMarginBreakdown => "PositionMarginBreakdown" {
+        token_amount: i128,
+        value: i128,
+        weighted_value: i128,
+        orders_value: i128,
+        unrealized_pnl: i128,
+        open_order_margin: u128,
+        collateral: i128,
+        collateral_buffer: i128,
+        margin_requirement: u128,
+        margin_requirement_plus_buffer: u128,
+        oracle_price: i64,
+        weight: u32,
+        market_index: u16,
+        is_isolated: bool,
+        has_isolated_liability: bool,
+    },
+    MarginBreakdown => "MarginBreakdown" {
+        spot_positions: [PositionMarginBreakdown; 8],
+        perp_positions: [PositionMarginBreakdown; 8],
+    },
+    FfiVersionInfo => "FfiVersionInfo" {
+        crate_version: RStr<'static>,
+        program_tag: RStr<'static>,
+        cluster: RStr<'static>,
+        program_id: Pubkey,
+        rustc_version: RStr<'static>,
+        layout_fingerprint: u64,
+    },
+    FfiFieldOffset => "FfiFieldOffset" {
+        name: RStr<'static>,
+        offset: usize,
+    },
+    FfiTypeLayout => "FfiTypeLayout" {
+        name: RStr<'static>,
+        size: usize,
+        align: usize,
+        fields: RSlice<'static, FfiFieldOffset>,
+    },
+    IncrementalMarginCalculation => "IncrementalMarginCalculation" {
+        total_collateral: i128,
+        total_collateral_buffer: i128,
+        margin_requirement: u128,
+        margin_requirement_plus_buffer: u128,
+        spot_collateral: [PositionCollateral; 8],
+        perp_collateral: [PositionCollateral; 8],
+        last_updated: u64,
+        user_custom_margin_ratio: u32,
+        margin_buffer: u32,
+        margin_type: MarginRequirementType,
+        user_high_leverage_mode: bool,
+        user_pool_id: u8,
+    },
+);
+
+/// C view of a `&mut [T]` field, a (pointer, length) pair
+#[repr(C)]
+pub(crate) struct MutSlice<T> {
+    ptr: *mut T,
+    len: usize,
+}
+
+impl<T: CType> CType for MutSlice<T> {
+    fn c_name() -> String {
+        format!("Slice_{}", ident::<T>())
+    }
+    fn define(header: &mut Header) {
+        let name = Self::c_name();
+        if header.begin(&name) {
+            let fields = vec![
+                field!(header, Self, ptr: *mut T),
+                field!(header, Self, len: usize),
+            ];
+            header.structure::<Self>(&name, fields);
+        }
+    }
+}
+
+/// C view of an `abi_stable` `RSlice`/`RStr`, a (pointer, length) pair
+#[repr(C)]
+struct ConstSlice<T> {
+    ptr: *const T,
+    len: usize,
+}
+
+impl<T: CType> CType for RSlice<'_, T> {
+    fn c_name() -> String {
+        format!("RSlice_{}", ident::<T>())
+    }
+    fn define(header: &mut Header) {
+        let name = Self::c_name();
+        if header.begin(&name) {
+            let fields = vec![
+                field!(header, ConstSlice<T>, ptr: *const T),
+                field!(header, ConstSlice<T>, len: usize),
+            ];
+            header.structure::<Self>(&name, fields);
+        }
+    }
+}
+
+impl<T: CType> CType for RSliceMut<'_, T> {
+    fn c_name() -> String {
+        format!("RSliceMut_{}", ident::<T>())
+    }
+    fn define(header: &mut Header) {
+        let name = Self::c_name();
+        if header.begin(&name) {
+            let fields = vec![
+                field!(header, MutSlice<T>, ptr: *mut T),
+                field!(header, MutSlice<T>, len: usize),
+            ];
+            header.structure::<Self>(&name, fields);
+        }
+    }
+}
+
+impl CType for RStr<'_> {
+    fn c_name() -> String {
+        "RStr".to_string()
+    }
+    fn define(header: &mut Header) {
+        if header.begin("RStr") {
+            let fields = vec![
+                field!(header, ConstSlice<u8>, ptr: *const u8),
+                field!(header, ConstSlice<u8>, len: usize),
+            ];
+            header.structure::<Self>("RStr", fields);
+        }
+    }
+}
+
+impl<T: CType> CType for &T {
+    const IS_POINTER: bool = true;
+
+    fn c_name() -> String {
+        format!("{} const *", T::c_name())
+    }
+    fn define(header: &mut Header) {
+        T::define(header);
+    }
+}
+
+impl<T: CType> CType for &mut T {
+    const IS_POINTER: bool = true;
+
+    fn c_name() -> String {
+        format!("{} *", T::c_name())
+    }
+    fn define(header: &mut Header) {
+        T::define(header);
+    }
+}
+
+impl<T: CType> CType for *const T {
+    fn c_name() -> String {
+        format!("{} const *", T::c_name())
+    }
+    fn define(header: &mut Header) {
+        T::define(header);
+    }
+}
+
+impl<T: CType> CType for *mut T {
+    fn c_name() -> String {
+        format!("{} *", T::c_name())
+    }
+    fn define(header: &mut Header) {
+        T::define(header);
+    }
+}
+
+impl<T: CType, const N: usize> CType for [T; N] {
+    fn c_name() -> String {
+        format!("{}[{N}]", T::c_name())
+    }
+    fn c_decl(name: &str) -> String {
+        T::c_decl(&format!("{name}[{N}]"))
+    }
+    fn define(header: &mut Header) {
+        T::define(header);
+    }
+}
+
+/// Same layout as `T`, used for caller-provided output buffers
+impl<T: CType> CType for MaybeUninit<T> {
+    const IS_POINTER: bool = T::IS_POINTER;
+    fn c_name() -> String {
+        T::c_name()
+    }
+    fn c_decl(name: &str) -> String {
+        T::c_decl(name)
+    }
+    fn define(header: &mut Header) {
+        T::define(header);
+    }
+}
+
+impl<T: CType> CType for Option<T> {
+    fn c_name() -> String {
+        if T::IS_POINTER {
+            // null pointer optimization
+            T::c_name()
+        } else {
+            format!("Option_{}", ident::<T>())
+        }
+    }
+    fn define(header: &mut Header) {
+        T::define(header);
+        if !T::IS_POINTER {
+            header.opaque::<Self>(&Self::c_name());
+        }
+    }
+}
+
+impl<A: CType, B: CType> CType for (A, B) {
+    fn c_name() -> String {
+        format!("Tuple_{}_{}", ident::<A>(), ident::<B>())
+    }
+    fn define(header: &mut Header) {
+        header.opaque::<Self>(&Self::c_name());
+    }
+}
+
+impl<A: CType, B: CType, C: CType> CType for (A, B, C) {
+    fn c_name() -> String {
+        format!("Tuple_{}_{}_{}", ident::<A>(), ident::<B>(), ident::<C>())
+    }
+    fn define(header: &mut Header) {
+        header.opaque::<Self>(&Self::c_name());
+    }
+}
+
+/// C declaration of a tagged union variant's payload, `None` for zero-sized payloads
+fn variant_decl<T: CType>() -> Option<String> {
+    (size_of::<T>() > 0).then(|| T::c_decl("value"))
+}
+
+impl<T: CType, E: CType> CType for RResult<T, E> {
+    fn c_name() -> String {
+        format!("RResult_{}_{}", ident::<T>(), ident::<E>())
+    }
+    fn define(header: &mut Header) {
+        let name = Self::c_name();
+        if header.begin(&name) {
+            T::define(header);
+            E::define(header);
+            header.tagged_union::<Self>(
+                &name,
+                &[("ok", variant_decl::<T>()), ("err", variant_decl::<E>())],
+            );
+        }
+    }
+}
+
+impl<T: CType> CType for ROption<T> {
+    fn c_name() -> String {
+        format!("ROption_{}", ident::<T>())
+    }
+    fn define(header: &mut Header) {
+        let name = Self::c_name();
+        if header.begin(&name) {
+            T::define(header);
+            header.tagged_union::<Self>(&name, &[("some", variant_decl::<T>()), ("none", None)]);
+        }
+    }
+}
+
+/// JSON string literal of `s`
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,228 @@
+//! Anchor account encoding/decoding from raw account data
+//!
+//! Account data is the 8-byte anchor discriminator followed by the account struct
+use std::mem::size_of;
+
+use anchor_lang::{AccountDeserialize, AccountSerialize, Discriminator, ZeroCopy};
+use drift_program::state::traits::Size;
+
+use crate::types::FfiErrorCode;
+
+/// Length of the anchor account discriminator
+pub const DISCRIMINATOR_LEN: usize = 8;
+
+/// Account data length of zero-copy account `T`
+pub const fn zero_copy_len<T: ZeroCopy>() -> usize {
+    DISCRIMINATOR_LEN + size_of::<T>()
+}
+
+/// Decode a zero-copy account (`User`, `PerpMarket`, etc.) from `data`
+///
+/// `data` must be exactly the account size, it need not be aligned
+pub fn decode_zero_copy<T: ZeroCopy>(data: &[u8]) -> Result<T, FfiErrorCode> {
+    check_discriminator::<T>(data)?;
+    if data.len() != zero_copy_len::<T>() {
+        return Err(FfiErrorCode::InvalidAccountSize);
+    }
+    Ok(bytemuck::pod_read_unaligned(&data[DISCRIMINATOR_LEN..]))
+}
+
+/// Decode a borsh serialized account (`State`) from `data`
+pub fn decode_borsh<T: AccountDeserialize + Discriminator + Size>(
+    data: &[u8],
+) -> Result<T, FfiErrorCode> {
+    check_discriminator::<T>(data)?;
+    if data.len() != T::SIZE {
+        return Err(FfiErrorCode::InvalidAccountSize);
+    }
+    T::try_deserialize_unchecked(&mut &data[..]).map_err(|_| FfiErrorCode::AccountDecode)
+}
+
+/// Encode zero-copy account `value` into `out`, returns the number of bytes written
+pub fn encode_zero_copy<T: ZeroCopy>(value: &T, out: &mut [u8]) -> Result<usize, FfiErrorCode> {
+    let len = zero_copy_len::<T>();
+    let out = out.get_mut(..len).ok_or(FfiErrorCode::BufferTooSmall)?;
+    out[..DISCRIMINATOR_LEN].copy_from_slice(&T::DISCRIMINATOR);
+    out[DISCRIMINATOR_LEN..].copy_from_slice(bytemuck::bytes_of(value));
+    Ok(len)
+}
+
+/// Encode borsh account `value` into `out` zero padded to the account size, returns the number of bytes written
+pub fn encode_borsh<T: AccountSerialize + Size>(
+    value: &T,
+    out: &mut [u8],
+) -> Result<usize, FfiErrorCode> {
+    let out = out.get_mut(..T::SIZE).ok_or(FfiErrorCode::BufferTooSmall)?;
+    out.fill(0);
+    let mut writer = &mut out[..];
+    value
+        .try_serialize(&mut writer)
+        .map_err(|_| FfiErrorCode::AccountEncode)?;
+    Ok(T::SIZE)
+}
+
+fn check_discriminator<T: Discriminator>(data: &[u8]) -> Result<(), FfiErrorCode> {
+    if data.get(..DISCRIMINATOR_LEN) == Some(&T::DISCRIMINATOR[..]) {
+        Ok(())
+    } else {
+        Err(FfiErrorCode::InvalidAccountDiscriminator)
+    }
+}
+
+/// Drift program owned account holding `value`
+#[cfg(test)]
+pub(crate) fn account_with_data<T: ZeroCopy>(value: &T) -> crate::types::AccountWithKey {
+    let mut data = vec![0_u8; zero_copy_len::<T>()];
+    encode_zero_copy(value, &mut data).unwrap();
+    crate::types::AccountWithKey {
+        key: solana_sdk::pubkey::Pubkey::new_unique(),
+        account: solana_sdk::account::Account {
+            data,
+            owner: drift_program::ID,
+            ..Default::default()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use drift_program::{
+        error::ErrorCode,
+        math::constants::PRICE_PRECISION_I64,
+        state::{
+            oracle::OracleSource,
+            perp_market::{PerpMarket, AMM},
+            spot_market::SpotMarket,
+            state::State,
+            user::User,
+        },
+    };
+    use solana_sdk::pubkey::Pubkey;
+
+    use super::*;
+    use crate::types::{AccountsList, FfiError, MarketState};
+
+    #[test]
+    fn zero_copy_round_trip() {
+        let user = User {
+            max_margin_ratio: 2_000,
+            ..Default::default()
+        };
+        let mut data = vec![0_u8; zero_copy_len::<User>() + 1];
+        let len = encode_zero_copy(&user, &mut data[1..]).unwrap();
+        assert_eq!(len, zero_copy_len::<User>());
+
+        // unaligned input decodes
+        let decoded: User = decode_zero_copy(&data[1..]).unwrap();
+        assert_eq!(decoded.max_margin_ratio, 2_000);
+    }
+
+    #[test]
+    fn decode_rejects_wrong_account() {
+        let mut data = vec![0_u8; zero_copy_len::<PerpMarket>()];
+        encode_zero_copy(&PerpMarket::default(), &mut data).unwrap();
+
+        assert_eq!(
+            decode_zero_copy::<User>(&data).err(),
+            Some(FfiErrorCode::InvalidAccountDiscriminator)
+        );
+        assert_eq!(
+            decode_zero_copy::<PerpMarket>(&data[..data.len() - 1]).err(),
+            Some(FfiErrorCode::InvalidAccountSize)
+        );
+        assert_eq!(
+            decode_zero_copy::<PerpMarket>(&[]).err(),
+            Some(FfiErrorCode::InvalidAccountDiscriminator)
+        );
+    }
+
+    #[test]
+    fn encode_rejects_small_buffer() {
+        let mut data = vec![0_u8; zero_copy_len::<User>() - 1];
+        assert_eq!(
+            encode_zero_copy(&User::default(), &mut data),
+            Err(FfiErrorCode::BufferTooSmall)
+        );
+        assert_eq!(
+            encode_borsh(&State::default(), &mut data[..State::SIZE - 1]),
+            Err(FfiErrorCode::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn borsh_round_trip() {
+        let state = State {
+            admin: Pubkey::new_unique(),
+            ..Default::default()
+        };
+        let mut data = vec![0_u8; State::SIZE];
+        assert_eq!(encode_borsh(&state, &mut data), Ok(State::SIZE));
+
+        let decoded: State = decode_borsh(&data).unwrap();
+        assert_eq!(decoded.admin, state.admin);
+    }
+
+    #[test]
+    fn market_state_from_accounts() {
+        let spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            decimals: 6,
+            ..Default::default()
+        };
+        let perp_market = PerpMarket {
+            market_index: 1,
+            amm: AMM {
+                oracle_source: OracleSource::QuoteAsset,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut spot_markets = [account_with_data(&spot_market)];
+        let mut perp_markets = [account_with_data(&perp_market)];
+        let mut accounts = AccountsList {
+            perp_markets: &mut perp_markets,
+            spot_markets: &mut spot_markets,
+            oracles: &mut [],
+            oracle_guard_rails: None,
+            latest_slot: 0,
+        };
+
+        let market_state = MarketState::from_accounts(&mut accounts).unwrap();
+        assert_eq!(market_state.get_spot_market(0).decimals, 6);
+        assert_eq!(market_state.get_perp_market(1).market_index, 1);
+        assert_eq!(
+            market_state.get_spot_oracle_price(0).unwrap().price,
+            PRICE_PRECISION_I64
+        );
+        assert_eq!(
+            market_state.get_perp_oracle_price(1).unwrap().price,
+            PRICE_PRECISION_I64
+        );
+    }
+
+    #[test]
+    fn market_state_from_accounts_missing_oracle() {
+        let perp_market = PerpMarket {
+            amm: AMM {
+                oracle: Pubkey::new_unique(),
+                oracle_source: OracleSource::Pyth,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut perp_markets = [account_with_data(&perp_market)];
+        let mut accounts = AccountsList {
+            perp_markets: &mut perp_markets,
+            spot_markets: &mut [],
+            oracles: &mut [],
+            oracle_guard_rails: None,
+            latest_slot: 0,
+        };
+
+        assert!(matches!(
+            MarketState::from_accounts(&mut accounts),
+            Err(FfiError::Program(ErrorCode::OracleNotFound))
+        ));
+    }
+}
@@ -0,0 +1,12 @@
+//! Write the C header and ABI manifest matching this build of the library
+//!
+//! usage: `drift-ffi-abi [out dir]`
+use std::path::PathBuf;
+
+fn main() -> std::io::Result<()> {
+    let out_dir = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."));
+    drift_ffi_sys::abi::write_artifacts(&out_dir)
+}
@@ -0,0 +1,144 @@
+//! Market and oracle maps prepared once from an `AccountsList` and reused across calls
+use drift_program::state::{
+    oracle_map::OracleMap, perp_market_map::PerpMarketMap, spot_market_map::SpotMarketMap,
+};
+use solana_sdk::{
+    account_info::{AccountInfo, IntoAccountInfo},
+    clock::Slot,
+};
+
+use crate::types::{AccountWithKey, AccountsList, FfiError};
+
+/// `SpotMarketMap`, `PerpMarketMap` and `OracleMap` loaded from a copy of an `AccountsList`
+///
+/// Per-call work is reduced to the user being evaluated. The context owns its accounts so the
+/// source `AccountsList` may be released after loading, it should be recreated when the accounts
+/// change e.g. once per slot.
+pub struct AccountsContext {
+    // the maps borrow `account_infos` which borrow `accounts`, fields drop in declaration order
+    pub(crate) perp_map: PerpMarketMap<'static>,
+    pub(crate) spot_map: SpotMarketMap<'static>,
+    pub(crate) oracle_map: OracleMap<'static>,
+    pub(crate) latest_slot: Slot,
+    _account_infos: [Owned<[AccountInfo<'static>]>; 3],
+    _accounts: [Owned<[AccountWithKey]>; 3],
+}
+
+impl AccountsContext {
+    /// Copy `accounts` and load the market and oracle maps
+    pub fn load(accounts: &AccountsList) -> Result<Self, FfiError> {
+        let mut perp_accounts = Owned::new(accounts.perp_markets.to_vec().into_boxed_slice());
+        let mut spot_accounts = Owned::new(accounts.spot_markets.to_vec().into_boxed_slice());
+        let mut oracle_accounts = Owned::new(accounts.oracles.to_vec().into_boxed_slice());
+
+        // SAFETY: each allocation is borrowed once and outlives its borrowers, see field order
+        let mut perp_infos = account_infos(unsafe { perp_accounts.get() });
+        let mut spot_infos = account_infos(unsafe { spot_accounts.get() });
+        let mut oracle_infos = account_infos(unsafe { oracle_accounts.get() });
+        let perp_iter: &'static [AccountInfo] = unsafe { perp_infos.get() };
+        let spot_iter: &'static [AccountInfo] = unsafe { spot_infos.get() };
+        let oracle_iter: &'static [AccountInfo] = unsafe { oracle_infos.get() };
+
+        let spot_map = SpotMarketMap::load(&Default::default(), &mut spot_iter.iter().peekable())?;
+        let perp_map = PerpMarketMap::load(&Default::default(), &mut perp_iter.iter().peekable())?;
+        let oracle_map = OracleMap::load(
+            &mut oracle_iter.iter().peekable(),
+            accounts.latest_slot,
+            accounts.oracle_guard_rails,
+        )?;
+
+        Ok(Self {
+            perp_map,
+            spot_map,
+            oracle_map,
+            latest_slot: accounts.latest_slot,
+            _account_infos: [perp_infos, spot_infos, oracle_infos],
+            _accounts: [perp_accounts, spot_accounts, oracle_accounts],
+        })
+    }
+}
+
+fn account_infos(accounts: &'static mut [AccountWithKey]) -> Owned<[AccountInfo<'static>]> {
+    Owned::new(
+        accounts
+            .iter_mut()
+            .map(IntoAccountInfo::into_account_info)
+            .collect(),
+    )
+}
+
+/// Heap allocation with a stable address, freed on drop
+struct Owned<T: ?Sized>(*mut T);
+
+impl<T: ?Sized> Owned<T> {
+    fn new(value: Box<T>) -> Self {
+        Self(Box::into_raw(value))
+    }
+
+    /// # Safety
+    /// Must be called at most once and the reference must not outlive `self`
+    unsafe fn get(&mut self) -> &'static mut T {
+        &mut *self.0
+    }
+}
+
+impl<T: ?Sized> Drop for Owned<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.0) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use drift_program::{
+        math::margin::{
+            calculate_margin_requirement_and_total_collateral_and_liability_info,
+            MarginRequirementType,
+        },
+        state::{
+            margin_calculation::MarginContext, perp_market::PerpMarket, spot_market::SpotMarket,
+            user::User,
+        },
+    };
+
+    use super::*;
+    use crate::accounts::account_with_data;
+
+    #[test]
+    fn context_outlives_source_accounts() {
+        let mut context = {
+            let mut perp_markets = [account_with_data(&PerpMarket {
+                market_index: 1,
+                ..Default::default()
+            })];
+            let mut spot_markets = [account_with_data(&SpotMarket {
+                market_index: 0,
+                ..Default::default()
+            })];
+            AccountsContext::load(&AccountsList {
+                perp_markets: &mut perp_markets,
+                spot_markets: &mut spot_markets,
+                oracles: &mut [],
+                oracle_guard_rails: None,
+                latest_slot: 10,
+            })
+            .unwrap()
+        };
+        assert_eq!(context.latest_slot, 10);
+        assert_eq!(context.perp_map.get_ref(&1).unwrap().market_index, 1);
+        assert_eq!(context.spot_map.get_ref(&0).unwrap().market_index, 0);
+
+        // reused across users
+        for _ in 0..2 {
+            let margin = calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &User::default(),
+                &context.perp_map,
+                &context.spot_map,
+                &mut context.oracle_map,
+                MarginContext::standard(MarginRequirementType::Initial),
+            )
+            .unwrap();
+            assert_eq!(margin.margin_requirement, 0);
+        }
+    }
+}
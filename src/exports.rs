@@ -1,17 +1,35 @@
 //!
 //! Define FFI for subset of drift program
 //!
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::HashMap,
+    ffi::{c_char, CString},
+    mem::{self, MaybeUninit},
+    num::NonZeroUsize,
+    panic::{self, AssertUnwindSafe},
+    sync::OnceLock,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use abi_stable::std_types::{
     ROption,
     RResult::{RErr, ROk},
+    RSlice, RSliceMut, RStr,
 };
 use anchor_lang::prelude::{AccountInfo, AccountLoader};
 use drift_program::{
-    controller::{position::PositionDirection, repeg::_update_amm},
+    controller::{
+        position::{add_new_position, get_position_index, PositionDirection},
+        repeg::_update_amm,
+    },
+    error::{DriftResult, ErrorCode},
     math::{self, amm::calculate_amm_available_liquidity, margin::MarginRequirementType},
     state::{
+        high_leverage_mode_config::HighLeverageModeConfig,
+        insurance_fund_stake::InsuranceFundStake,
         oracle::{get_oracle_price as get_oracle_price_, OraclePriceData, OracleSource},
         oracle_map::OracleMap,
         order_params::PlaceOrderOptions,
@@ -22,9 +40,11 @@ use drift_program::{
         spot_market::{SpotBalanceType, SpotMarket},
         spot_market_map::SpotMarketMap,
         state::{FeeTier, State, ValidityGuardRails},
-        user::{Order, PerpPosition, SpotPosition, User},
+        traits::Size,
+        user::{MarketType, Order, PerpPosition, SpotPosition, User, UserStats},
     },
 };
+use fxhash::FxBuildHasher;
 use solana_sdk::{
     account::Account,
     account_info::IntoAccountInfo,
@@ -33,18 +53,89 @@ use solana_sdk::{
 };
 
 use crate::{
-    margin::IncrementalMarginCalculation,
+    accounts,
+    context::AccountsContext,
+    margin::{IncrementalMarginCalculation, MarginBreakdown},
     types::{
         compat::{self},
-        AccountsList, FfiResult, IsolatedMarginCalculation, MMOraclePriceData, MarginCalculation,
-        MarginContextMode, MarketState,
+        AccountRiskMetrics, AccountsList, FfiError, FfiErrorCode, FfiResult, FfiTypeLayout,
+        FfiVersionInfo, IsolatedMarginCalculation, MMOraclePriceData, MarginCalculation,
+        MarginContextMode, MarketState, MaxWithdrawAmount, PlacePerpOrderResult, PriceShock,
     },
 };
 
 /// Return the FFI crate version
+///
+/// Returns a rust `String` which is not FFI-safe, prefer `ffi_version_info`
 #[no_mangle]
 pub extern "C" fn ffi_version() -> String {
-    env!("CARGO_PKG_VERSION").to_string()
+    ffi_call_infallible(|| env!("CARGO_PKG_VERSION").to_string())
+}
+
+/// Return the crate version, drift program tag, cluster and ID, rustc version and layout fingerprint of the library
+#[no_mangle]
+pub extern "C" fn ffi_version_info() -> FfiVersionInfo {
+    ffi_call_infallible(|| FfiVersionInfo {
+        crate_version: RStr::from_str(env!("CARGO_PKG_VERSION")),
+        program_tag: RStr::from_str(env!("DRIFT_PROGRAM_TAG")),
+        cluster: RStr::from_str(crate::types::CLUSTER),
+        program_id: drift_program::ID,
+        rustc_version: RStr::from_str(env!("DRIFT_FFI_RUSTC_VERSION")),
+        layout_fingerprint: crate::abi::layout_fingerprint(),
+    })
+}
+
+/// Return the message of the last panic caught at the FFI boundary on the calling thread
+///
+/// Returns null if no panic has been caught. The string is owned by the library and stays valid
+/// until the next panic caught on the same thread.
+#[no_mangle]
+pub extern "C" fn ffi_last_panic_message() -> *const c_char {
+    LAST_PANIC_MESSAGE.with(|message| {
+        message
+            .borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Return the name of an `FfiResult` error code e.g. `InsufficientCollateral`
+///
+/// Returns null for unknown codes. Covers every drift program `ErrorCode` and FFI layer
+/// `FfiErrorCode`. The string is owned by the library and valid for its lifetime.
+#[no_mangle]
+pub extern "C" fn ffi_error_name(code: u32) -> *const c_char {
+    ffi_call_infallible(|| {
+        error_description(code).map_or(std::ptr::null(), |description| description.name.as_ptr())
+    })
+}
+
+/// Return the message of an `FfiResult` error code e.g. `Insufficient collateral`
+///
+/// Same lookup rules as `ffi_error_name`
+#[no_mangle]
+pub extern "C" fn ffi_error_message(code: u32) -> *const c_char {
+    ffi_call_infallible(|| {
+        error_description(code).map_or(std::ptr::null(), |description| description.message.as_ptr())
+    })
+}
+
+/// Return the drift program ID the library is compiled for
+///
+/// Differs by cluster feature (`mainnet-beta`/`devnet`), accounts passed to the library must be owned by it
+#[no_mangle]
+pub extern "C" fn ffi_program_id() -> Pubkey {
+    ffi_call_infallible(|| drift_program::ID)
+}
+
+/// Return the size, alignment and key field offsets of on-chain types as compiled into this library
+///
+/// Covers `User`, `PerpMarket`, `SpotMarket`, `State`, `Order`, `PerpPosition`, `SpotPosition`,
+/// `OraclePriceData` and the `compat` integers. SDKs should compare these with their own layouts
+/// at load time and refuse to run on a mismatch. The returned slice is valid for the library lifetime.
+#[no_mangle]
+pub extern "C" fn ffi_type_layouts() -> RSlice<'static, FfiTypeLayout> {
+    ffi_call_infallible(|| RSlice::from_slice(crate::abi::type_layouts()))
 }
 
 #[no_mangle]
@@ -53,14 +144,14 @@ pub extern "C" fn oracle_get_oracle_price(
     price_oracle: &mut (Pubkey, Account),
     clock_slot: Slot,
 ) -> FfiResult<OraclePriceData> {
-    to_ffi_result(
+    ffi_call(|| {
         get_oracle_price_(
             &oracle_source,
             &price_oracle.into_account_info(),
             clock_slot,
         )
-        .map(|o| unsafe { std::mem::transmute(o) }),
-    )
+        .map(|o| unsafe { std::mem::transmute(o) })
+    })
 }
 
 #[no_mangle]
@@ -71,13 +162,15 @@ pub extern "C" fn math_calculate_auction_price(
     oracle_price: ROption<i64>,
     is_prediction_market: bool,
 ) -> FfiResult<u64> {
-    to_ffi_result(math::auction::calculate_auction_price(
-        order,
-        slot,
-        tick_size,
-        oracle_price.into(),
-        is_prediction_market,
-    ))
+    ffi_call(|| {
+        math::auction::calculate_auction_price(
+            order,
+            slot,
+            tick_size,
+            oracle_price.into(),
+            is_prediction_market,
+        )
+    })
 }
 
 #[no_mangle]
@@ -86,67 +179,42 @@ pub extern "C" fn math_calculate_margin_requirement_and_total_collateral_and_lia
     accounts: &mut AccountsList,
     margin_context: MarginContextMode,
 ) -> FfiResult<MarginCalculation> {
-    let spot_accounts = accounts
-        .spot_markets
-        .iter_mut()
-        .map(IntoAccountInfo::into_account_info)
-        .collect::<Vec<_>>();
-    let spot_map =
-        SpotMarketMap::load(&Default::default(), &mut spot_accounts.iter().peekable()).unwrap();
-
-    let perp_accounts = accounts
-        .perp_markets
-        .iter_mut()
-        .map(IntoAccountInfo::into_account_info)
-        .collect::<Vec<_>>();
-    let perp_map =
-        PerpMarketMap::load(&Default::default(), &mut perp_accounts.iter().peekable()).unwrap();
+    ffi_call(|| -> Result<_, ErrorCode> {
+        let spot_accounts = accounts
+            .spot_markets
+            .iter_mut()
+            .map(IntoAccountInfo::into_account_info)
+            .collect::<Vec<_>>();
+        let spot_map =
+            SpotMarketMap::load(&Default::default(), &mut spot_accounts.iter().peekable())?;
 
-    let oracle_accounts = accounts
-        .oracles
-        .iter_mut()
-        .map(IntoAccountInfo::into_account_info)
-        .collect::<Vec<_>>();
-    let mut oracle_map = OracleMap::load(
-        &mut oracle_accounts.iter().peekable(),
-        accounts.latest_slot,
-        accounts.oracle_guard_rails,
-    )
-    .unwrap();
+        let perp_accounts = accounts
+            .perp_markets
+            .iter_mut()
+            .map(IntoAccountInfo::into_account_info)
+            .collect::<Vec<_>>();
+        let perp_map =
+            PerpMarketMap::load(&Default::default(), &mut perp_accounts.iter().peekable())?;
 
-    let margin_calculation = drift_program::math::margin::calculate_margin_requirement_and_total_collateral_and_liability_info(
-        user,
-        &perp_map,
-        &spot_map,
-        &mut oracle_map,
-        margin_context.into(),
-    );
+        let oracle_accounts = accounts
+            .oracles
+            .iter_mut()
+            .map(IntoAccountInfo::into_account_info)
+            .collect::<Vec<_>>();
+        let mut oracle_map = OracleMap::load(
+            &mut oracle_accounts.iter().peekable(),
+            accounts.latest_slot,
+            accounts.oracle_guard_rails,
+        )?;
 
-    // map to ffi compatible u/i128s
-    let m = margin_calculation.map(|m| {
-        let mut isolated_margin_calculations = [IsolatedMarginCalculation::default(); 8];
-        for (idx, (k, v)) in m.isolated_margin_calculations.into_iter().enumerate() {
-            isolated_margin_calculations[idx] = IsolatedMarginCalculation {
-                market_index: k,
-                margin_requirement: v.margin_requirement.into(),
-                total_collateral: v.total_collateral.into(),
-                total_collateral_buffer: v.total_collateral_buffer.into(),
-                margin_requirement_plus_buffer: v.margin_requirement_plus_buffer.into(),
-            };
-        }
-        MarginCalculation {
-            total_collateral: m.total_collateral.into(),
-            margin_requirement: m.margin_requirement.into(),
-            with_perp_isolated_liability: m.with_perp_isolated_liability,
-            with_spot_isolated_liability: m.with_spot_isolated_liability,
-            total_spot_asset_value: m.total_spot_asset_value.into(),
-            total_spot_liability_value: m.total_spot_liability_value.into(),
-            total_perp_liability_value: m.total_perp_liability_value.into(),
-            total_perp_pnl: m.total_perp_pnl.into(),
-            isolated_margin_calculations,
-        }
-    });
-    to_ffi_result(m)
+        calculate_margin_requirement_and_total_collateral_and_liability_info(
+            user,
+            &perp_map,
+            &spot_map,
+            &mut oracle_map,
+            margin_context,
+        )
+    })
 }
 
 #[no_mangle]
@@ -154,9 +222,9 @@ pub extern "C" fn math_calculate_net_user_pnl(
     amm: &AMM,
     oracle_price: i64,
 ) -> FfiResult<compat::i128> {
-    to_ffi_result(
-        drift_program::math::amm::calculate_net_user_pnl(amm, oracle_price).map(compat::i128),
-    )
+    ffi_call(|| {
+        drift_program::math::amm::calculate_net_user_pnl(amm, oracle_price).map(compat::i128)
+    })
 }
 
 #[no_mangle]
@@ -167,7 +235,7 @@ pub extern "C" fn simulate_update_amm(
     now: u64,
     slot: Slot,
 ) -> FfiResult<compat::i128> {
-    to_ffi_result(
+    ffi_call(|| {
         _update_amm(
             market,
             &unsafe { std::mem::transmute(mm_oracle_price_data) },
@@ -175,8 +243,8 @@ pub extern "C" fn simulate_update_amm(
             now as i64,
             slot,
         )
-        .map(|x| x.into()),
-    )
+        .map(|x| x.into())
+    })
 }
 
 #[no_mangle]
@@ -188,17 +256,23 @@ pub extern "C" fn math_calculate_base_asset_amount_for_amm_to_fulfill(
     existing_base_asset_amount: i64,
     fee_tier: &FeeTier,
 ) -> FfiResult<(u64, Option<u64>)> {
-    let res = drift_program::math::orders::calculate_base_asset_amount_for_amm_to_fulfill(
-        order,
-        market,
-        limit_price,
-        override_fill_price,
-        existing_base_asset_amount,
-        fee_tier,
-    );
-    to_ffi_result(res)
+    ffi_call(|| {
+        drift_program::math::orders::calculate_base_asset_amount_for_amm_to_fulfill(
+            order,
+            market,
+            limit_price,
+            override_fill_price,
+            existing_base_asset_amount,
+            fee_tier,
+        )
+    })
 }
 
+/// Simulate placing a perp order for `user`, returns whether the order was placed
+///
+/// Order expiry and auction timing use a clock at `accounts.latest_slot` with the current wall clock
+/// time, see `orders_place_perp_order_with_clock` for reproducible results. Use
+/// `orders_place_perp_order_with_result` for the updated user and the placed order.
 #[no_mangle]
 pub extern "C" fn orders_place_perp_order<'a>(
     user: &User,
@@ -208,63 +282,213 @@ pub extern "C" fn orders_place_perp_order<'a>(
     high_leverage_mode_config: Option<&'a AccountInfo<'a>>,
     revenue_share_order: &mut Option<&'a mut RevenueShareOrder>,
 ) -> FfiResult<bool> {
-    let spot_accounts = accounts
-        .spot_markets
-        .iter_mut()
-        .map(IntoAccountInfo::into_account_info)
-        .collect::<Vec<_>>();
-    let spot_map =
-        SpotMarketMap::load(&Default::default(), &mut spot_accounts.iter().peekable()).unwrap();
+    ffi_call(|| {
+        let clock = wall_clock(accounts.latest_slot);
+        place_perp_order_from_accounts(
+            user,
+            state,
+            order_params,
+            accounts,
+            clock,
+            high_leverage_mode_config,
+            revenue_share_order,
+        )
+        .map(|_| true)
+    })
+}
 
-    let perp_accounts = accounts
-        .perp_markets
-        .iter_mut()
-        .map(IntoAccountInfo::into_account_info)
-        .collect::<Vec<_>>();
-    let perp_map =
-        PerpMarketMap::load(&Default::default(), &mut perp_accounts.iter().peekable()).unwrap();
+/// Simulate placing a perp order for `user`, returns the updated user and the placed order
+///
+/// Same clock as `orders_place_perp_order`
+#[no_mangle]
+pub extern "C" fn orders_place_perp_order_with_result<'a>(
+    user: &User,
+    state: &State,
+    order_params: &crate::types::OrderParams,
+    accounts: &mut AccountsList,
+    high_leverage_mode_config: Option<&'a AccountInfo<'a>>,
+    revenue_share_order: &mut Option<&'a mut RevenueShareOrder>,
+) -> FfiResult<PlacePerpOrderResult> {
+    ffi_call(|| {
+        let clock = wall_clock(accounts.latest_slot);
+        place_perp_order_from_accounts(
+            user,
+            state,
+            order_params,
+            accounts,
+            clock,
+            high_leverage_mode_config,
+            revenue_share_order,
+        )
+    })
+}
 
-    let oracle_accounts = accounts
-        .oracles
-        .iter_mut()
-        .map(IntoAccountInfo::into_account_info)
-        .collect::<Vec<_>>();
-    let mut oracle_map = OracleMap::load(
-        &mut oracle_accounts.iter().peekable(),
-        accounts.latest_slot,
-        accounts.oracle_guard_rails,
-    )
-    .unwrap();
+/// Simulate placing a perp order for `user` at `clock`, returns the updated user and the placed
+/// order
+///
+/// Same as `orders_place_perp_order_with_result` with order expiry and auction timing taken from
+/// `clock`
+#[no_mangle]
+pub extern "C" fn orders_place_perp_order_with_clock<'a>(
+    user: &User,
+    state: &State,
+    order_params: &crate::types::OrderParams,
+    accounts: &mut AccountsList,
+    clock: &Clock,
+    high_leverage_mode_config: Option<&'a AccountInfo<'a>>,
+    revenue_share_order: &mut Option<&'a mut RevenueShareOrder>,
+) -> FfiResult<PlacePerpOrderResult> {
+    ffi_call(|| {
+        place_perp_order_from_accounts(
+            user,
+            state,
+            order_params,
+            accounts,
+            clock.clone(),
+            high_leverage_mode_config,
+            revenue_share_order,
+        )
+    })
+}
 
-    // has no epoch info but this is un-required for order placement
-    let local_clock = Clock {
-        slot: accounts.latest_slot,
-        epoch_start_timestamp: 0,
-        epoch: 0,
-        leader_schedule_epoch: 0,
-        unix_timestamp: SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64,
-    };
+/// Largest base asset amount `user` can order in the `market_index` perp market in `direction`
+/// while meeting initial margin, per the program's `calculate_max_perp_order_size`
+#[no_mangle]
+pub extern "C" fn orders_calculate_max_perp_order_size(
+    user: &User,
+    accounts: &mut AccountsList,
+    market_index: u16,
+    direction: PositionDirection,
+) -> FfiResult<u64> {
+    ffi_call(|| -> Result<_, ErrorCode> {
+        let spot_accounts = accounts
+            .spot_markets
+            .iter_mut()
+            .map(IntoAccountInfo::into_account_info)
+            .collect::<Vec<_>>();
+        let spot_map =
+            SpotMarketMap::load(&Default::default(), &mut spot_accounts.iter().peekable())?;
 
-    let hlm_loader = high_leverage_mode_config
-        .map(|x| AccountLoader::try_from_unchecked(&drift_program::ID, x).unwrap());
-    let res = drift_program::controller::orders::place_perp_order(
-        state,
-        &mut user.clone(),
-        user.authority,
-        &perp_map,
-        &spot_map,
-        &mut oracle_map,
-        &hlm_loader,
-        &local_clock,
-        order_params.into(),
-        PlaceOrderOptions::default(),
-        revenue_share_order,
-    );
+        let perp_accounts = accounts
+            .perp_markets
+            .iter_mut()
+            .map(IntoAccountInfo::into_account_info)
+            .collect::<Vec<_>>();
+        let perp_map =
+            PerpMarketMap::load(&Default::default(), &mut perp_accounts.iter().peekable())?;
+
+        let oracle_accounts = accounts
+            .oracles
+            .iter_mut()
+            .map(IntoAccountInfo::into_account_info)
+            .collect::<Vec<_>>();
+        let mut oracle_map = OracleMap::load(
+            &mut oracle_accounts.iter().peekable(),
+            accounts.latest_slot,
+            accounts.oracle_guard_rails,
+        )?;
+
+        calculate_max_perp_order_size(
+            user,
+            market_index,
+            direction,
+            &perp_map,
+            &spot_map,
+            &mut oracle_map,
+        )
+    })
+}
+
+/// Load the market and oracle maps of `accounts` into a context owned by the library
+///
+/// The context keeps a copy of the accounts, `accounts` may be released once this returns. The
+/// returned handle must be released with `accounts_context_free`
+#[no_mangle]
+pub extern "C" fn accounts_context_new(accounts: &AccountsList) -> FfiResult<*mut AccountsContext> {
+    ffi_call(|| AccountsContext::load(accounts).map(|context| Box::into_raw(Box::new(context))))
+}
+
+/// Release an `AccountsContext` created by `accounts_context_new`
+///
+/// # Safety
+/// `context` must be null or a handle returned by `accounts_context_new` that has not been freed
+#[no_mangle]
+pub unsafe extern "C" fn accounts_context_free(context: *mut AccountsContext) {
+    ffi_call_infallible(|| {
+        if !context.is_null() {
+            drop(Box::from_raw(context));
+        }
+    })
+}
+
+/// Same as `math_calculate_margin_requirement_and_total_collateral_and_liability_info` using
+/// the prepared maps of `context`
+#[no_mangle]
+pub extern "C" fn accounts_context_calculate_margin_requirement_and_total_collateral_and_liability_info(
+    context: &mut AccountsContext,
+    user: &User,
+    margin_context: MarginContextMode,
+) -> FfiResult<MarginCalculation> {
+    ffi_call(|| {
+        calculate_margin_requirement_and_total_collateral_and_liability_info(
+            user,
+            &context.perp_map,
+            &context.spot_map,
+            &mut context.oracle_map,
+            margin_context,
+        )
+    })
+}
+
+/// Same as `orders_place_perp_order_with_clock` using the prepared maps of `context`
+///
+/// Without `clock` a clock at `context.latest_slot` with the current wall clock time is used, as in
+/// `orders_place_perp_order`
+#[no_mangle]
+pub extern "C" fn accounts_context_place_perp_order<'a>(
+    context: &mut AccountsContext,
+    user: &User,
+    state: &State,
+    order_params: &crate::types::OrderParams,
+    clock: Option<&Clock>,
+    high_leverage_mode_config: Option<&'a AccountInfo<'a>>,
+    revenue_share_order: &mut Option<&'a mut RevenueShareOrder>,
+) -> FfiResult<PlacePerpOrderResult> {
+    ffi_call(|| {
+        place_perp_order(
+            user,
+            state,
+            order_params,
+            &context.perp_map,
+            &context.spot_map,
+            &mut context.oracle_map,
+            clock
+                .cloned()
+                .unwrap_or_else(|| wall_clock(context.latest_slot)),
+            high_leverage_mode_config,
+            revenue_share_order,
+        )
+    })
+}
 
-    to_ffi_result(res.map(|_| true))
+/// Same as `orders_calculate_max_perp_order_size` using the prepared maps of `context`
+#[no_mangle]
+pub extern "C" fn accounts_context_calculate_max_perp_order_size(
+    context: &mut AccountsContext,
+    user: &User,
+    market_index: u16,
+    direction: PositionDirection,
+) -> FfiResult<u64> {
+    ffi_call(|| {
+        calculate_max_perp_order_size(
+            user,
+            market_index,
+            direction,
+            &context.perp_map,
+            &context.spot_map,
+            &mut context.oracle_map,
+        )
+    })
 }
 
 #[no_mangle]
@@ -273,19 +497,19 @@ pub extern "C" fn order_calculate_auction_params_for_trigger_order(
     oracle_price: &OraclePriceData,
     perp_market: Option<&PerpMarket>,
 ) -> FfiResult<(u8, i64, i64)> {
-    to_ffi_result(
+    ffi_call(|| {
         drift_program::math::auction::calculate_auction_params_for_trigger_order(
             order,
             oracle_price,
             20,
             perp_market,
-        ),
-    )
+        )
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn order_is_limit_order(order: &Order) -> bool {
-    order.is_limit_order()
+    ffi_call_infallible(|| order.is_limit_order())
 }
 
 #[no_mangle]
@@ -298,19 +522,21 @@ pub extern "C" fn order_get_limit_price(
     is_prediction_market: bool,
     pmm_params: Option<ProtectedMakerParams>,
 ) -> FfiResult<Option<u64>> {
-    to_ffi_result(order.get_limit_price(
-        valid_oracle_price,
-        fallback_price,
-        slot,
-        tick_size,
-        is_prediction_market,
-        pmm_params,
-    ))
+    ffi_call(|| {
+        order.get_limit_price(
+            valid_oracle_price,
+            fallback_price,
+            slot,
+            tick_size,
+            is_prediction_market,
+            pmm_params,
+        )
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn order_is_resting_limit_order(order: &Order, slot: u64) -> FfiResult<bool> {
-    to_ffi_result(order.is_resting_limit_order(slot))
+    ffi_call(|| order.is_resting_limit_order(slot))
 }
 
 #[no_mangle]
@@ -320,8 +546,10 @@ pub extern "C" fn order_params_will_auction_params_sanitize(
     oracle_price: i64,
     is_signed_msg: bool,
 ) -> FfiResult<bool> {
-    let mut order_params: drift_program::state::order_params::OrderParams = order_params.into();
-    to_ffi_result(order_params.update_perp_auction_params(perp_market, oracle_price, is_signed_msg))
+    ffi_call(|| {
+        let mut order_params: drift_program::state::order_params::OrderParams = order_params.into();
+        order_params.update_perp_auction_params(perp_market, oracle_price, is_signed_msg)
+    })
 }
 
 #[no_mangle]
@@ -331,23 +559,25 @@ pub extern "C" fn order_params_update_perp_auction_params(
     oracle_price: i64,
     is_signed_msg: bool,
 ) {
-    // Convert to program type, update, then write back into the caller's struct
-    let mut order_params_2: drift_program::state::order_params::OrderParams =
-        (order_params as &crate::types::OrderParams).into();
-    order_params_2.update_perp_auction_params(perp_market, oracle_price, is_signed_msg);
-    *order_params = (&order_params_2).into();
+    ffi_call_infallible(|| {
+        // Convert to program type, update, then write back into the caller's struct
+        let mut order_params_2: drift_program::state::order_params::OrderParams =
+            (order_params as &crate::types::OrderParams).into();
+        order_params_2.update_perp_auction_params(perp_market, oracle_price, is_signed_msg);
+        *order_params = (&order_params_2).into();
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn perp_market_get_protected_maker_params(
     market: &PerpMarket,
 ) -> ProtectedMakerParams {
-    market.get_protected_maker_params()
+    ffi_call_infallible(|| market.get_protected_maker_params())
 }
 
 #[no_mangle]
 pub extern "C" fn order_triggered(order: &Order) -> bool {
-    order.triggered()
+    ffi_call_infallible(|| order.triggered())
 }
 
 #[no_mangle]
@@ -357,12 +587,12 @@ pub extern "C" fn perp_market_get_margin_ratio(
     margin_type: MarginRequirementType,
     high_leverage_mode: bool,
 ) -> FfiResult<u32> {
-    to_ffi_result(market.get_margin_ratio(size.0, margin_type, high_leverage_mode))
+    ffi_call(|| market.get_margin_ratio(size.0, margin_type, high_leverage_mode))
 }
 
 #[no_mangle]
 pub extern "C" fn perp_market_get_open_interest(market: &PerpMarket) -> compat::u128 {
-    market.get_open_interest().into()
+    ffi_call_infallible(|| market.get_open_interest().into())
 }
 
 #[no_mangle]
@@ -372,7 +602,7 @@ pub extern "C" fn perp_market_get_mm_oracle_price_data(
     clock_slot: u64,
     oracle_guard_rails: &ValidityGuardRails,
 ) -> FfiResult<MMOraclePriceData> {
-    to_ffi_result(
+    ffi_call(|| {
         market
             .get_mm_oracle_price_data(oracle_price_data, clock_slot, oracle_guard_rails)
             .map(|m| MMOraclePriceData {
@@ -383,8 +613,8 @@ pub extern "C" fn perp_market_get_mm_oracle_price_data(
                 mm_exchange_diff_bps: m.get_mm_exchange_diff_bps().into(),
                 exchange_oracle_price_data: m.get_exchange_oracle_price_data(),
                 safe_oracle_price_data: m.get_safe_oracle_price_data(),
-            }),
-    )
+            })
+    })
 }
 
 #[no_mangle]
@@ -394,7 +624,7 @@ pub extern "C" fn perp_market_get_trigger_price(
     now: i64,
     use_median_price: bool,
 ) -> FfiResult<u64> {
-    to_ffi_result(market.get_trigger_price(oracle_price, now, use_median_price))
+    ffi_call(|| market.get_trigger_price(oracle_price, now, use_median_price))
 }
 
 #[no_mangle]
@@ -404,15 +634,12 @@ pub extern "C" fn perp_market_get_fallback_price(
     oracle_price: i64,
     seconds_til_order_expiry: i64,
 ) -> FfiResult<u64> {
-    let res = match calculate_amm_available_liquidity(&market.amm, &direction) {
-        Ok(liq) => {
-            market
-                .amm
-                .get_fallback_price(&direction, liq, oracle_price, seconds_til_order_expiry)
-        }
-        Err(err) => Err(err),
-    };
-    to_ffi_result(res)
+    ffi_call(|| {
+        let liq = calculate_amm_available_liquidity(&market.amm, &direction)?;
+        market
+            .amm
+            .get_fallback_price(&direction, liq, oracle_price, seconds_til_order_expiry)
+    })
 }
 
 #[no_mangle]
@@ -420,7 +647,7 @@ pub extern "C" fn perp_position_get_unrealized_pnl(
     position: &PerpPosition,
     oracle_price: i64,
 ) -> FfiResult<compat::i128> {
-    to_ffi_result(position.get_unrealized_pnl(oracle_price).map(compat::i128))
+    ffi_call(|| position.get_unrealized_pnl(oracle_price).map(compat::i128))
 }
 
 #[no_mangle]
@@ -429,21 +656,21 @@ pub extern "C" fn perp_position_get_claimable_pnl(
     oracle_price: i64,
     pnl_pool_excess: compat::i128,
 ) -> FfiResult<compat::i128> {
-    to_ffi_result(
+    ffi_call(|| {
         position
             .get_claimable_pnl(oracle_price, pnl_pool_excess.0)
-            .map(compat::i128),
-    )
+            .map(compat::i128)
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn perp_position_is_available(position: &PerpPosition) -> bool {
-    position.is_available()
+    ffi_call_infallible(|| position.is_available())
 }
 
 #[no_mangle]
 pub extern "C" fn perp_position_is_open_position(position: &PerpPosition) -> bool {
-    position.is_open_position()
+    ffi_call_infallible(|| position.is_open_position())
 }
 
 #[no_mangle]
@@ -452,8 +679,11 @@ pub extern "C" fn perp_position_worst_case_base_asset_amount(
     oracle_price: i64,
     contract_type: ContractType,
 ) -> FfiResult<compat::i128> {
-    let res = position.worst_case_base_asset_amount(oracle_price, contract_type);
-    to_ffi_result(res.map(compat::i128))
+    ffi_call(|| {
+        position
+            .worst_case_base_asset_amount(oracle_price, contract_type)
+            .map(compat::i128)
+    })
 }
 
 #[no_mangle]
@@ -463,7 +693,7 @@ pub extern "C" fn spot_market_get_asset_weight(
     oracle_price: i64,
     margin_requirement_type: MarginRequirementType,
 ) -> FfiResult<u32> {
-    to_ffi_result(market.get_asset_weight(size.0, oracle_price, &margin_requirement_type))
+    ffi_call(|| market.get_asset_weight(size.0, oracle_price, &margin_requirement_type))
 }
 
 #[no_mangle]
@@ -472,7 +702,7 @@ pub extern "C" fn spot_market_get_liability_weight(
     size: compat::u128,
     margin_requirement_type: MarginRequirementType,
 ) -> FfiResult<u32> {
-    to_ffi_result(market.get_liability_weight(size.0, &margin_requirement_type))
+    ffi_call(|| market.get_liability_weight(size.0, &margin_requirement_type))
 }
 
 #[no_mangle]
@@ -480,12 +710,12 @@ pub extern "C" fn spot_market_get_margin_ratio(
     market: &SpotMarket,
     margin_type: MarginRequirementType,
 ) -> FfiResult<u32> {
-    to_ffi_result(market.get_margin_ratio(&margin_type))
+    ffi_call(|| market.get_margin_ratio(&margin_type))
 }
 
 #[no_mangle]
 pub extern "C" fn spot_position_is_available(position: &SpotPosition) -> bool {
-    position.is_available()
+    ffi_call_infallible(|| position.is_available())
 }
 
 #[no_mangle]
@@ -493,7 +723,7 @@ pub extern "C" fn spot_position_get_signed_token_amount(
     position: &SpotPosition,
     market: &SpotMarket,
 ) -> FfiResult<compat::i128> {
-    to_ffi_result(position.get_signed_token_amount(market).map(compat::i128))
+    ffi_call(|| position.get_signed_token_amount(market).map(compat::i128))
 }
 
 #[no_mangle]
@@ -501,7 +731,7 @@ pub extern "C" fn spot_position_get_token_amount(
     position: &SpotPosition,
     market: &SpotMarket,
 ) -> FfiResult<compat::u128> {
-    to_ffi_result(position.get_token_amount(market).map(compat::u128))
+    ffi_call(|| position.get_token_amount(market).map(compat::u128))
 }
 
 #[no_mangle]
@@ -510,10 +740,10 @@ pub extern "C" fn spot_balance_get_token_amount(
     spot_market: &SpotMarket,
     balance_type: &SpotBalanceType,
 ) -> FfiResult<compat::u128> {
-    to_ffi_result(
+    ffi_call(|| {
         drift_program::math::spot_balance::get_token_amount(balance.0, spot_market, balance_type)
-            .map(compat::u128),
-    )
+            .map(compat::u128)
+    })
 }
 
 #[no_mangle]
@@ -521,7 +751,7 @@ pub extern "C" fn user_get_spot_position(
     user: &User,
     market_index: u16,
 ) -> FfiResult<&SpotPosition> {
-    to_ffi_result(user.get_spot_position(market_index))
+    ffi_call(|| user.get_spot_position(market_index))
 }
 
 #[no_mangle]
@@ -529,7 +759,7 @@ pub extern "C" fn user_get_perp_position(
     user: &User,
     market_index: u16,
 ) -> FfiResult<&PerpPosition> {
-    to_ffi_result(user.get_perp_position(market_index))
+    ffi_call(|| user.get_perp_position(market_index))
 }
 
 #[no_mangle]
@@ -538,9 +768,10 @@ pub extern "C" fn user_update_perp_position_max_margin_ratio(
     market_index: u16,
     margin_ratio: u16,
 ) -> FfiResult<()> {
-    to_ffi_result(user.update_perp_position_max_margin_ratio(market_index, margin_ratio))
+    ffi_call(|| user.update_perp_position_max_margin_ratio(market_index, margin_ratio))
 }
 
+/// Compute the simplified margin requirement of `user`
 #[no_mangle]
 pub extern "C" fn margin_calculate_simplified_margin_requirement(
     user: &User,
@@ -548,16 +779,242 @@ pub extern "C" fn margin_calculate_simplified_margin_requirement(
     margin_type: MarginRequirementType,
     margin_buffer: u32,
 ) -> FfiResult<crate::types::SimplifiedMarginCalculation> {
-    let result = crate::margin::calculate_simplified_margin_requirement(
-        user,
-        market_state,
-        margin_type,
-        margin_buffer,
-    );
+    ffi_call(|| {
+        crate::margin::calculate_simplified_margin_requirement(
+            user,
+            market_state,
+            margin_type,
+            margin_buffer,
+        )
+        .map(Into::into)
+    })
+}
+
+/// Compute the simplified margin requirement of `user` with strict oracle prices
+///
+/// Spot and quote prices are bounded by the 5min oracle TWAP, as the program does for withdrawals
+#[no_mangle]
+pub extern "C" fn margin_calculate_strict_simplified_margin_requirement(
+    user: &User,
+    market_state: &MarketState,
+    margin_type: MarginRequirementType,
+    margin_buffer: u32,
+) -> FfiResult<crate::types::SimplifiedMarginCalculation> {
+    ffi_call(|| {
+        crate::margin::calculate_strict_simplified_margin_requirement(
+            user,
+            market_state,
+            margin_type,
+            margin_buffer,
+        )
+        .map(Into::into)
+    })
+}
+
+/// Per-position contributions to `margin_calculate_simplified_margin_requirement`, indexed like the
+/// user's spot and perp positions
+#[no_mangle]
+pub extern "C" fn margin_calculate_simplified_margin_breakdown(
+    user: &User,
+    market_state: &MarketState,
+    margin_type: MarginRequirementType,
+    margin_buffer: u32,
+    strict: bool,
+) -> FfiResult<MarginBreakdown> {
+    ffi_call(|| {
+        crate::margin::calculate_simplified_margin_breakdown(
+            user,
+            market_state,
+            margin_type,
+            margin_buffer,
+            strict,
+        )
+    })
+}
+
+/// Oracle price of the `market_type` market at which `user` reaches zero maintenance free
+/// collateral with all other prices held fixed, see `risk::calculate_liquidation_price`
+#[no_mangle]
+pub extern "C" fn risk_calculate_liquidation_price(
+    user: &User,
+    market_state: &MarketState,
+    market_type: MarketType,
+    market_index: u16,
+) -> FfiResult<ROption<i64>> {
+    ffi_call(|| {
+        crate::risk::calculate_liquidation_price(user, market_state, market_type, market_index)
+            .map(Into::into)
+    })
+}
+
+/// Largest base asset amount `user` can order in the `market_index` perp market in `direction`,
+/// see `risk::calculate_max_perp_order_size`
+#[no_mangle]
+pub extern "C" fn risk_calculate_max_perp_order_size(
+    user: &User,
+    market_state: &MarketState,
+    market_index: u16,
+    direction: PositionDirection,
+) -> FfiResult<u64> {
+    ffi_call(|| {
+        crate::risk::calculate_max_perp_order_size(user, market_state, market_index, direction)
+    })
+}
+
+/// Largest token amounts `user` can withdraw from the `market_index` spot market with and without
+/// borrowing, see `risk::calculate_max_withdraw_amount`
+#[no_mangle]
+pub extern "C" fn risk_calculate_max_withdraw_amount(
+    user: &User,
+    market_state: &MarketState,
+    market_index: u16,
+) -> FfiResult<MaxWithdrawAmount> {
+    ffi_call(|| crate::risk::calculate_max_withdraw_amount(user, market_state, market_index))
+}
+
+/// Maintenance health, leverage, margin ratio and distance to liquidation of `user`'s cross account
+/// and isolated perp positions, see `risk::calculate_risk_metrics`
+#[no_mangle]
+pub extern "C" fn risk_calculate_risk_metrics(
+    user: &User,
+    market_state: &MarketState,
+) -> FfiResult<AccountRiskMetrics> {
+    ffi_call(|| crate::risk::calculate_risk_metrics(user, market_state))
+}
+
+/// Risk metrics from a maintenance `breakdown` of either margin engine
+///
+/// Fails with `MathError` if the breakdown values overflow the metrics
+#[no_mangle]
+pub extern "C" fn risk_calculate_risk_metrics_from_breakdown(
+    breakdown: &MarginBreakdown,
+) -> FfiResult<AccountRiskMetrics> {
+    ffi_call(|| crate::risk::calculate_risk_metrics_from_breakdown(breakdown))
+}
+
+/// Compute the simplified margin requirement of every user in `users`, writing each result to the
+/// same index of `out`
+///
+/// `strict` selects `margin_calculate_strict_simplified_margin_requirement`. `out` must hold at
+/// least as many entries as `users`. Users are split across up to `threads` threads (`0` uses the
+/// available parallelism)
+#[no_mangle]
+pub extern "C" fn margin_calculate_simplified_margin_requirement_batch(
+    users: RSlice<User>,
+    market_state: &MarketState,
+    margin_type: MarginRequirementType,
+    margin_buffer: u32,
+    strict: bool,
+    threads: usize,
+    mut out: RSliceMut<MaybeUninit<FfiResult<crate::types::SimplifiedMarginCalculation>>>,
+) -> FfiResult<()> {
+    ffi_call(|| {
+        let out = out
+            .get_mut(..users.len())
+            .ok_or(FfiErrorCode::BufferTooSmall)?;
+        let threads = match threads {
+            0 => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            threads => threads,
+        };
+        let chunk_size = users.len().div_ceil(threads).max(1);
+        let calculate = simplified_margin_requirement_fn(strict);
+        // a panic in any thread propagates here and fails the whole batch
+        thread::scope(|scope| {
+            let workers = users
+                .chunks(chunk_size)
+                .zip(out.chunks_mut(chunk_size))
+                .map(|(users, out)| {
+                    scope.spawn(move || {
+                        for (user, out) in users.iter().zip(out) {
+                            out.write(to_ffi_result(
+                                calculate(user, market_state, margin_type, margin_buffer)
+                                    .map(Into::into),
+                            ));
+                        }
+                    })
+                })
+                .collect();
+            join_workers(workers);
+        });
+        Ok::<_, FfiErrorCode>(())
+    })
+}
 
-    to_ffi_result(result.map(Into::into))
+/// Compute the simplified margin requirement of every user in `users` under every price shock
+/// scenario in `scenarios`, writing user `i` under scenario `j` to `out[i * scenarios.len() + j]`
+///
+/// Each scenario shocks a copy of `market_state`, see `risk::apply_price_shocks`, and a scenario
+/// that cannot be applied fails every user under it. `out` must hold at least
+/// `users.len() * scenarios.len()` entries. Users are split across up to `threads` threads (`0`
+/// uses the available parallelism)
+#[no_mangle]
+pub extern "C" fn risk_calculate_stress_grid(
+    users: RSlice<User>,
+    market_state: &MarketState,
+    scenarios: RSlice<RSlice<PriceShock>>,
+    margin_type: MarginRequirementType,
+    margin_buffer: u32,
+    strict: bool,
+    threads: usize,
+    mut out: RSliceMut<MaybeUninit<FfiResult<crate::types::SimplifiedMarginCalculation>>>,
+) -> FfiResult<()> {
+    ffi_call(|| {
+        let out = users
+            .len()
+            .checked_mul(scenarios.len())
+            .and_then(|len| out.get_mut(..len))
+            .ok_or(FfiErrorCode::BufferTooSmall)?;
+        if scenarios.is_empty() {
+            return Ok(());
+        }
+        let market_states: Vec<_> = scenarios
+            .iter()
+            .map(|shocks| crate::risk::apply_price_shocks(market_state, shocks))
+            .collect();
+        let threads = match threads {
+            0 => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            threads => threads,
+        };
+        let chunk_size = users.len().div_ceil(threads).max(1);
+        let calculate = simplified_margin_requirement_fn(strict);
+        let market_states = &market_states;
+        // a panic in any thread propagates here and fails the whole grid
+        thread::scope(|scope| {
+            let workers = users
+                .chunks(chunk_size)
+                .zip(out.chunks_mut(chunk_size * scenarios.len()))
+                .map(|(users, out)| {
+                    scope.spawn(move || {
+                        for (user, out) in users.iter().zip(out.chunks_mut(market_states.len())) {
+                            for (market_state, out) in market_states.iter().zip(out) {
+                                out.write(to_ffi_result(
+                                    market_state
+                                        .as_ref()
+                                        .map_err(|err| *err)
+                                        .and_then(|market_state| {
+                                            calculate(
+                                                user,
+                                                market_state,
+                                                margin_type,
+                                                margin_buffer,
+                                            )
+                                        })
+                                        .map(Into::into),
+                                ));
+                            }
+                        }
+                    })
+                })
+                .collect();
+            join_workers(workers);
+        });
+        Ok::<_, FfiErrorCode>(())
+    })
 }
 
+/// Calculate the incremental margin of `user` from `market_state`
+///
+/// Aborts on a missing market or math error, prefer `incremental_margin_calculation_try_from_user`
 #[no_mangle]
 pub extern "C" fn incremental_margin_calculation_from_user(
     user: &User,
@@ -566,15 +1023,42 @@ pub extern "C" fn incremental_margin_calculation_from_user(
     timestamp: u64,
     margin_buffer: u32,
 ) -> IncrementalMarginCalculation {
-    IncrementalMarginCalculation::from_user(
-        user,
-        market_state,
-        margin_type,
-        timestamp,
-        margin_buffer,
-    )
+    ffi_call_infallible(|| {
+        IncrementalMarginCalculation::from_user(
+            user,
+            market_state,
+            margin_type,
+            timestamp,
+            margin_buffer,
+        )
+    })
+}
+
+/// Calculate the incremental margin of `user` from `market_state`
+///
+/// Returns the program error on a missing market or math error
+#[no_mangle]
+pub extern "C" fn incremental_margin_calculation_try_from_user(
+    user: &User,
+    market_state: &MarketState,
+    margin_type: MarginRequirementType,
+    timestamp: u64,
+    margin_buffer: u32,
+) -> FfiResult<IncrementalMarginCalculation> {
+    ffi_call(|| {
+        IncrementalMarginCalculation::try_from_user(
+            user,
+            market_state,
+            margin_type,
+            timestamp,
+            margin_buffer,
+        )
+    })
 }
 
+/// Update a single spot position of `this` and recalculate its totals
+///
+/// Aborts on a missing market or math error, prefer `incremental_margin_calculation_update_spot_position_checked`
 #[no_mangle]
 pub extern "C" fn incremental_margin_calculation_update_spot_position(
     this: &mut IncrementalMarginCalculation,
@@ -582,9 +1066,25 @@ pub extern "C" fn incremental_margin_calculation_update_spot_position(
     market_state: &MarketState,
     timestamp: u64,
 ) {
-    this.update_spot_position(spot_position, market_state, timestamp);
+    ffi_call_infallible(|| this.update_spot_position(spot_position, market_state, timestamp))
+}
+
+/// Update a single spot position of `this` and recalculate its totals
+///
+/// Returns the program error on a missing market or math error, `this` is unchanged on error
+#[no_mangle]
+pub extern "C" fn incremental_margin_calculation_update_spot_position_checked(
+    this: &mut IncrementalMarginCalculation,
+    spot_position: &SpotPosition,
+    market_state: &MarketState,
+    timestamp: u64,
+) -> FfiResult<()> {
+    ffi_call(|| this.try_update_spot_position(spot_position, market_state, timestamp))
 }
 
+/// Update a single perp position of `this` and recalculate its totals
+///
+/// Aborts on a missing market or math error, prefer `incremental_margin_calculation_update_perp_position_checked`
 #[no_mangle]
 pub extern "C" fn incremental_margin_calculation_update_perp_position(
     this: &mut IncrementalMarginCalculation,
@@ -592,17 +1092,685 @@ pub extern "C" fn incremental_margin_calculation_update_perp_position(
     market_state: &MarketState,
     timestamp: u64,
 ) {
-    this.update_perp_position(perp_position, market_state, timestamp);
+    ffi_call_infallible(|| this.update_perp_position(perp_position, market_state, timestamp))
 }
 
-//
-// Helpers
-//
-/// Convert Drift program result into an FFI compatible version
-#[inline]
-pub(crate) fn to_ffi_result<T>(result: Result<T, drift_program::error::ErrorCode>) -> FfiResult<T> {
-    match result {
+/// Update a single perp position of `this` and recalculate its totals
+///
+/// Returns the program error on a missing market or math error, `this` is unchanged on error
+#[no_mangle]
+pub extern "C" fn incremental_margin_calculation_update_perp_position_checked(
+    this: &mut IncrementalMarginCalculation,
+    perp_position: &PerpPosition,
+    market_state: &MarketState,
+    timestamp: u64,
+) -> FfiResult<()> {
+    ffi_call(|| this.try_update_perp_position(perp_position, market_state, timestamp))
+}
+
+/// Per-position contributions of `user` with the settings of `this`, recomputed from
+/// `market_state`
+#[no_mangle]
+pub extern "C" fn incremental_margin_calculation_breakdown(
+    this: &IncrementalMarginCalculation,
+    user: &User,
+    market_state: &MarketState,
+) -> FfiResult<MarginBreakdown> {
+    ffi_call(|| this.breakdown(user, market_state))
+}
+
+/// Create an empty `MarketState` owned by the library
+///
+/// The returned handle must be released with `market_state_free`
+#[no_mangle]
+pub extern "C" fn market_state_new() -> *mut MarketState {
+    ffi_call_infallible(|| Box::into_raw(Box::default()))
+}
+
+/// Create a `MarketState` owned by the library from raw market and oracle accounts
+///
+/// Every perp and spot market is decoded and its oracle priced with the market's own
+/// `oracle_source` at `accounts.latest_slot`. The returned handle must be released with
+/// `market_state_free`
+#[no_mangle]
+pub extern "C" fn market_state_from_accounts(
+    accounts: &mut AccountsList,
+) -> FfiResult<*mut MarketState> {
+    ffi_call(|| MarketState::from_accounts(accounts).map(|state| Box::into_raw(Box::new(state))))
+}
+
+/// Release a `MarketState` created by `market_state_new` or `market_state_from_accounts`
+///
+/// # Safety
+/// `market_state` must be null or a handle returned by `market_state_new` or
+/// `market_state_from_accounts` that has not been freed
+#[no_mangle]
+pub unsafe extern "C" fn market_state_free(market_state: *mut MarketState) {
+    ffi_call_infallible(|| {
+        if !market_state.is_null() {
+            drop(Box::from_raw(market_state));
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_set_spot_market(
+    market_state: &mut MarketState,
+    market: &SpotMarket,
+) {
+    ffi_call_infallible(|| market_state.set_spot_market(*market))
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_set_perp_market(
+    market_state: &mut MarketState,
+    market: &PerpMarket,
+) {
+    ffi_call_infallible(|| market_state.set_perp_market(*market))
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_set_spot_oracle_price(
+    market_state: &mut MarketState,
+    market_index: u16,
+    price_data: OraclePriceData,
+) {
+    ffi_call_infallible(|| market_state.set_spot_oracle_price(market_index, price_data))
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_set_perp_oracle_price(
+    market_state: &mut MarketState,
+    market_index: u16,
+    price_data: OraclePriceData,
+) {
+    ffi_call_infallible(|| market_state.set_perp_oracle_price(market_index, price_data))
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_set_spot_pyth_price(
+    market_state: &mut MarketState,
+    market_index: u16,
+    price: i64,
+) {
+    ffi_call_infallible(|| market_state.set_spot_pyth_price(market_index, price))
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_set_perp_pyth_price(
+    market_state: &mut MarketState,
+    market_index: u16,
+    price: i64,
+) {
+    ffi_call_infallible(|| market_state.set_perp_pyth_price(market_index, price))
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_set_pyth_oracle_diff_threshold_bps(
+    market_state: &mut MarketState,
+    threshold_bps: u64,
+) {
+    ffi_call_infallible(|| {
+        market_state.pyth_oracle_diff_threshold_bps = threshold_bps;
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_get_spot_market(
+    market_state: &MarketState,
+    market_index: u16,
+) -> FfiResult<&SpotMarket> {
+    ffi_call(|| market_state.try_get_spot_market(market_index))
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_get_perp_market(
+    market_state: &MarketState,
+    market_index: u16,
+) -> FfiResult<&PerpMarket> {
+    ffi_call(|| market_state.try_get_perp_market(market_index))
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_get_spot_oracle_price(
+    market_state: &MarketState,
+    market_index: u16,
+) -> FfiResult<OraclePriceData> {
+    ffi_call(|| {
+        market_state
+            .get_spot_oracle_price(market_index)
+            .copied()
+            .ok_or(ErrorCode::OracleNotFound)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_get_perp_oracle_price(
+    market_state: &MarketState,
+    market_index: u16,
+) -> FfiResult<OraclePriceData> {
+    ffi_call(|| {
+        market_state
+            .get_perp_oracle_price(market_index)
+            .copied()
+            .ok_or(ErrorCode::OracleNotFound)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_get_spot_pyth_price(
+    market_state: &MarketState,
+    market_index: u16,
+) -> FfiResult<i64> {
+    ffi_call(|| {
+        market_state
+            .spot_pyth_prices
+            .get(&market_index)
+            .copied()
+            .ok_or(ErrorCode::OracleNotFound)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_get_perp_pyth_price(
+    market_state: &MarketState,
+    market_index: u16,
+) -> FfiResult<i64> {
+    ffi_call(|| {
+        market_state
+            .perp_pyth_prices
+            .get(&market_index)
+            .copied()
+            .ok_or(ErrorCode::OracleNotFound)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn market_state_get_pyth_oracle_diff_threshold_bps(
+    market_state: &MarketState,
+) -> u64 {
+    ffi_call_infallible(|| market_state.pyth_oracle_diff_threshold_bps)
+}
+
+/// Decode a `User` from raw account data, discriminator included
+#[no_mangle]
+pub extern "C" fn user_decode(data: RSlice<u8>) -> FfiResult<User> {
+    ffi_call(|| accounts::decode_zero_copy(&data))
+}
+
+/// Decode a `UserStats` from raw account data, discriminator included
+#[no_mangle]
+pub extern "C" fn user_stats_decode(data: RSlice<u8>) -> FfiResult<UserStats> {
+    ffi_call(|| accounts::decode_zero_copy(&data))
+}
+
+/// Decode a `PerpMarket` from raw account data, discriminator included
+#[no_mangle]
+pub extern "C" fn perp_market_decode(data: RSlice<u8>) -> FfiResult<PerpMarket> {
+    ffi_call(|| accounts::decode_zero_copy(&data))
+}
+
+/// Decode a `SpotMarket` from raw account data, discriminator included
+#[no_mangle]
+pub extern "C" fn spot_market_decode(data: RSlice<u8>) -> FfiResult<SpotMarket> {
+    ffi_call(|| accounts::decode_zero_copy(&data))
+}
+
+/// Decode the `State` from raw account data, discriminator included
+#[no_mangle]
+pub extern "C" fn state_decode(data: RSlice<u8>) -> FfiResult<State> {
+    ffi_call(|| accounts::decode_borsh(&data))
+}
+
+/// Decode an `InsuranceFundStake` from raw account data, discriminator included
+#[no_mangle]
+pub extern "C" fn insurance_fund_stake_decode(data: RSlice<u8>) -> FfiResult<InsuranceFundStake> {
+    ffi_call(|| accounts::decode_zero_copy(&data))
+}
+
+/// Decode a `HighLeverageModeConfig` from raw account data, discriminator included
+#[no_mangle]
+pub extern "C" fn high_leverage_mode_config_decode(
+    data: RSlice<u8>,
+) -> FfiResult<HighLeverageModeConfig> {
+    ffi_call(|| accounts::decode_zero_copy(&data))
+}
+
+/// Return the account data length of a `User`, discriminator included
+#[no_mangle]
+pub extern "C" fn user_encoded_len() -> usize {
+    ffi_call_infallible(accounts::zero_copy_len::<User>)
+}
+
+/// Encode a `User` into `out` as raw account data, discriminator included
+///
+/// `out` must hold at least `user_encoded_len()` bytes, returns the number of bytes written
+#[no_mangle]
+pub extern "C" fn user_encode(user: &User, mut out: RSliceMut<u8>) -> FfiResult<usize> {
+    ffi_call(|| accounts::encode_zero_copy(user, &mut out))
+}
+
+/// Return the account data length of a `PerpMarket`, discriminator included
+#[no_mangle]
+pub extern "C" fn perp_market_encoded_len() -> usize {
+    ffi_call_infallible(accounts::zero_copy_len::<PerpMarket>)
+}
+
+/// Encode a `PerpMarket` into `out` as raw account data, discriminator included
+///
+/// `out` must hold at least `perp_market_encoded_len()` bytes, returns the number of bytes written
+#[no_mangle]
+pub extern "C" fn perp_market_encode(
+    perp_market: &PerpMarket,
+    mut out: RSliceMut<u8>,
+) -> FfiResult<usize> {
+    ffi_call(|| accounts::encode_zero_copy(perp_market, &mut out))
+}
+
+/// Return the account data length of a `SpotMarket`, discriminator included
+#[no_mangle]
+pub extern "C" fn spot_market_encoded_len() -> usize {
+    ffi_call_infallible(accounts::zero_copy_len::<SpotMarket>)
+}
+
+/// Encode a `SpotMarket` into `out` as raw account data, discriminator included
+///
+/// `out` must hold at least `spot_market_encoded_len()` bytes, returns the number of bytes written
+#[no_mangle]
+pub extern "C" fn spot_market_encode(
+    spot_market: &SpotMarket,
+    mut out: RSliceMut<u8>,
+) -> FfiResult<usize> {
+    ffi_call(|| accounts::encode_zero_copy(spot_market, &mut out))
+}
+
+/// Return the account data length of the `State`, discriminator included
+#[no_mangle]
+pub extern "C" fn state_encoded_len() -> usize {
+    ffi_call_infallible(|| State::SIZE)
+}
+
+/// Encode the `State` into `out` as raw account data, discriminator included
+///
+/// `out` must hold at least `state_encoded_len()` bytes, returns the number of bytes written
+#[no_mangle]
+pub extern "C" fn state_encode(state: &State, mut out: RSliceMut<u8>) -> FfiResult<usize> {
+    ffi_call(|| accounts::encode_borsh(state, &mut out))
+}
+
+//
+// Helpers
+//
+thread_local! {
+    /// Message of the last panic caught at the FFI boundary on this thread
+    static LAST_PANIC_MESSAGE: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Run an export body, catching any panic so it never unwinds across the FFI boundary
+///
+/// A panic is returned as `FfiErrorCode::Panic` with its message kept for `ffi_last_panic_message`
+#[inline]
+pub(crate) fn ffi_call<T, E: Into<FfiError>>(f: impl FnOnce() -> Result<T, E>) -> FfiResult<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => to_ffi_result(result),
+        Err(payload) => {
+            let message = panic_message(payload.as_ref()).replace('\0', "");
+            LAST_PANIC_MESSAGE.with(|last| {
+                *last.borrow_mut() = Some(CString::new(message).unwrap_or_default());
+            });
+            to_ffi_result(Err(FfiErrorCode::Panic))
+        }
+    }
+}
+
+/// Run an export body that has no error channel
+///
+/// Aborts the process on panic, unwinding across the FFI boundary is undefined behaviour
+#[inline]
+pub(crate) fn ffi_call_infallible<T>(f: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => value,
+        Err(payload) => {
+            eprintln!(
+                "drift-ffi: aborting on panic in infallible call: {}",
+                panic_message(payload.as_ref())
+            );
+            std::process::abort();
+        }
+    }
+}
+
+/// `calculate_strict_simplified_margin_requirement` if `strict`, otherwise
+/// `calculate_simplified_margin_requirement`
+fn simplified_margin_requirement_fn(
+    strict: bool,
+) -> fn(
+    &User,
+    &MarketState,
+    MarginRequirementType,
+    u32,
+) -> DriftResult<crate::margin::SimplifiedMarginCalculation> {
+    if strict {
+        crate::margin::calculate_strict_simplified_margin_requirement
+    } else {
+        crate::margin::calculate_simplified_margin_requirement
+    }
+}
+
+/// Join all `workers`, re-raising the first worker panic with its original payload
+///
+/// `thread::scope` would replace it with a generic message, losing it for `ffi_last_panic_message`
+fn join_workers(workers: Vec<thread::ScopedJoinHandle<'_, ()>>) {
+    let mut first_panic = None;
+    for worker in workers {
+        if let Err(payload) = worker.join() {
+            first_panic.get_or_insert(payload);
+        }
+    }
+    if let Some(payload) = first_panic {
+        panic::resume_unwind(payload);
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Convert Drift program (or FFI layer) result into an FFI compatible version
+#[inline]
+pub(crate) fn to_ffi_result<T, E: Into<FfiError>>(result: Result<T, E>) -> FfiResult<T> {
+    match result {
         Ok(r) => ROk(r),
-        Err(err) => RErr(err.into()),
+        Err(err) => RErr(err.into().code()),
+    }
+}
+
+/// C string descriptions of an `FfiResult` error code
+struct ErrorDescription {
+    name: CString,
+    message: CString,
+}
+
+impl ErrorDescription {
+    fn new(err: &FfiError) -> Self {
+        Self {
+            name: CString::new(err.name()).unwrap_or_default(),
+            message: CString::new(err.message()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Descriptions of every FFI layer and drift program error code
+fn error_descriptions() -> &'static HashMap<u32, ErrorDescription, FxBuildHasher> {
+    static DESCRIPTIONS: OnceLock<HashMap<u32, ErrorDescription, FxBuildHasher>> = OnceLock::new();
+    DESCRIPTIONS.get_or_init(|| {
+        FfiErrorCode::ALL
+            .iter()
+            .map(|code| FfiError::Ffi(*code))
+            .chain(program_error_codes().map(FfiError::Program))
+            .map(|err| (err.code(), ErrorDescription::new(&err)))
+            .collect()
+    })
+}
+
+fn error_description(code: u32) -> Option<&'static ErrorDescription> {
+    error_descriptions().get(&code)
+}
+
+/// Every drift program `ErrorCode` variant, in code order
+///
+/// `#[error_code]` variants are numbered from 0 without gaps and `Option<ErrorCode>` keeps `None` in
+/// the first value past the last variant, which gives the variant count
+fn program_error_codes() -> impl Iterator<Item = ErrorCode> {
+    const _: () = assert!(mem::size_of::<Option<ErrorCode>>() == mem::size_of::<u32>());
+    // SAFETY: same size and every bit pattern is a valid `u32`
+    let count = unsafe { mem::transmute::<Option<ErrorCode>, u32>(None) };
+    // SAFETY: every value below `count` is a variant discriminant
+    (0..count).map(|discriminant| unsafe { mem::transmute::<u32, ErrorCode>(discriminant) })
+}
+
+/// Program margin calculation mapped to FFI compatible types
+fn calculate_margin_requirement_and_total_collateral_and_liability_info(
+    user: &User,
+    perp_map: &PerpMarketMap,
+    spot_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    margin_context: MarginContextMode,
+) -> Result<MarginCalculation, ErrorCode> {
+    let m = drift_program::math::margin::calculate_margin_requirement_and_total_collateral_and_liability_info(
+        user,
+        perp_map,
+        spot_map,
+        oracle_map,
+        margin_context.into(),
+    )?;
+
+    // map to ffi compatible u/i128s
+    let mut isolated_margin_calculations = [IsolatedMarginCalculation::default(); 8];
+    for (idx, (k, v)) in m.isolated_margin_calculations.into_iter().enumerate() {
+        isolated_margin_calculations[idx] = IsolatedMarginCalculation {
+            market_index: k,
+            margin_requirement: v.margin_requirement.into(),
+            total_collateral: v.total_collateral.into(),
+            total_collateral_buffer: v.total_collateral_buffer.into(),
+            margin_requirement_plus_buffer: v.margin_requirement_plus_buffer.into(),
+        };
+    }
+    Ok(MarginCalculation {
+        total_collateral: m.total_collateral.into(),
+        margin_requirement: m.margin_requirement.into(),
+        with_perp_isolated_liability: m.with_perp_isolated_liability,
+        with_spot_isolated_liability: m.with_spot_isolated_liability,
+        total_spot_asset_value: m.total_spot_asset_value.into(),
+        total_spot_liability_value: m.total_spot_liability_value.into(),
+        total_perp_liability_value: m.total_perp_liability_value.into(),
+        total_perp_pnl: m.total_perp_pnl.into(),
+        isolated_margin_calculations,
+    })
+}
+
+/// `place_perp_order` with markets and oracles loaded from `accounts`
+fn place_perp_order_from_accounts<'a>(
+    user: &User,
+    state: &State,
+    order_params: &crate::types::OrderParams,
+    accounts: &mut AccountsList,
+    clock: Clock,
+    high_leverage_mode_config: Option<&'a AccountInfo<'a>>,
+    revenue_share_order: &mut Option<&'a mut RevenueShareOrder>,
+) -> Result<PlacePerpOrderResult, FfiError> {
+    let spot_accounts = accounts
+        .spot_markets
+        .iter_mut()
+        .map(IntoAccountInfo::into_account_info)
+        .collect::<Vec<_>>();
+    let spot_map = SpotMarketMap::load(&Default::default(), &mut spot_accounts.iter().peekable())?;
+
+    let perp_accounts = accounts
+        .perp_markets
+        .iter_mut()
+        .map(IntoAccountInfo::into_account_info)
+        .collect::<Vec<_>>();
+    let perp_map = PerpMarketMap::load(&Default::default(), &mut perp_accounts.iter().peekable())?;
+
+    let oracle_accounts = accounts
+        .oracles
+        .iter_mut()
+        .map(IntoAccountInfo::into_account_info)
+        .collect::<Vec<_>>();
+    let mut oracle_map = OracleMap::load(
+        &mut oracle_accounts.iter().peekable(),
+        accounts.latest_slot,
+        accounts.oracle_guard_rails,
+    )?;
+
+    place_perp_order(
+        user,
+        state,
+        order_params,
+        &perp_map,
+        &spot_map,
+        &mut oracle_map,
+        clock,
+        high_leverage_mode_config,
+        revenue_share_order,
+    )
+}
+
+/// Simulate placing a perp order for `user` on a copy of it
+///
+/// Returns the updated copy with the index of the placed order and the order itself, or the
+/// program error if the order is rejected
+#[allow(clippy::too_many_arguments)]
+fn place_perp_order<'a>(
+    user: &User,
+    state: &State,
+    order_params: &crate::types::OrderParams,
+    perp_map: &PerpMarketMap,
+    spot_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: Clock,
+    high_leverage_mode_config: Option<&'a AccountInfo<'a>>,
+    revenue_share_order: &mut Option<&'a mut RevenueShareOrder>,
+) -> Result<PlacePerpOrderResult, FfiError> {
+    let hlm_loader = high_leverage_mode_config
+        .map(|x| AccountLoader::try_from_unchecked(&drift_program::ID, x))
+        .transpose()
+        .map_err(|_| FfiErrorCode::AccountDecode)?;
+    let mut user = *user;
+    let user_key = user.authority;
+    let order_id = user.next_order_id;
+    drift_program::controller::orders::place_perp_order(
+        state,
+        &mut user,
+        user_key,
+        perp_map,
+        spot_map,
+        oracle_map,
+        &hlm_loader,
+        &clock,
+        order_params.into(),
+        PlaceOrderOptions::default(),
+        revenue_share_order,
+    )?;
+
+    let order_index = user.get_order_index(order_id)?;
+    Ok(PlacePerpOrderResult {
+        order: user.orders[order_index],
+        order_index,
+        user,
+    })
+}
+
+/// Max perp order size of `user` via the program, opening a position slot if `user` has none in
+/// `market_index`
+fn calculate_max_perp_order_size(
+    user: &User,
+    market_index: u16,
+    direction: PositionDirection,
+    perp_map: &PerpMarketMap,
+    spot_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+) -> Result<u64, ErrorCode> {
+    let mut user = *user;
+    let position_index = get_position_index(&user.perp_positions, market_index)
+        .or_else(|_| add_new_position(&mut user.perp_positions, market_index))?;
+    math::orders::calculate_max_perp_order_size(
+        &user,
+        position_index,
+        market_index,
+        direction,
+        perp_map,
+        spot_map,
+        oracle_map,
+    )
+}
+
+/// `Clock` at `slot` with the current wall clock time
+///
+/// Has no epoch info but this is un-required for order placement
+fn wall_clock(slot: Slot) -> Clock {
+    Clock {
+        slot,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64,
+    }
+}
+
+// signatures of the exports above for the C header/ABI manifest, see `build.rs`
+include!(concat!(env!("OUT_DIR"), "/exports_abi.rs"));
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use anchor_lang::error::ERROR_CODE_OFFSET;
+
+    use super::*;
+
+    #[test]
+    fn worker_panic_message_is_reported() {
+        let result = ffi_call(|| {
+            thread::scope(|scope| {
+                join_workers(vec![
+                    scope.spawn(|| {}),
+                    scope.spawn(|| panic!("worker failed")),
+                ]);
+            });
+            Ok::<_, FfiErrorCode>(())
+        });
+
+        assert_eq!(result.unwrap_err(), u32::from(FfiErrorCode::Panic));
+        let message = unsafe { CStr::from_ptr(ffi_last_panic_message()) };
+        assert_eq!(message.to_str().unwrap(), "worker failed");
+    }
+
+    #[test]
+    fn error_descriptions_cover_all_codes() {
+        let name = |code: u32| {
+            unsafe { CStr::from_ptr(ffi_error_name(code)) }
+                .to_str()
+                .unwrap()
+        };
+
+        // described without the code having been returned first
+        let code = u32::from(ErrorCode::InsufficientCollateral);
+        assert_eq!(name(code), "InsufficientCollateral");
+        assert!(!ffi_error_message(code).is_null());
+        assert_eq!(name(FfiErrorCode::BufferTooSmall.into()), "BufferTooSmall");
+        assert!(ffi_error_name(0).is_null());
+        assert!(ffi_error_message(0).is_null());
+
+        // codes are unique across the FFI layer and drift program
+        assert_eq!(
+            error_descriptions().len(),
+            FfiErrorCode::ALL.len() + program_error_codes().count()
+        );
+    }
+
+    #[test]
+    fn program_error_codes_match_error_code() {
+        let codes: Vec<ErrorCode> = program_error_codes().collect();
+        for (index, code) in codes.iter().enumerate() {
+            assert_eq!(u32::from(*code), ERROR_CODE_OFFSET + index as u32);
+        }
+        for code in [
+            ErrorCode::InsufficientCollateral,
+            ErrorCode::MathError,
+            ErrorCode::SpotMarketNotFound,
+            ErrorCode::PerpMarketNotFound,
+        ] {
+            let index = (u32::from(code) - ERROR_CODE_OFFSET) as usize;
+            assert_eq!(codes[index].name(), code.name());
+        }
     }
 }
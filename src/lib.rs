@@ -1,4 +1,15 @@
 //! Drift program FFI exports
+#[cfg(all(feature = "mainnet-beta", feature = "devnet"))]
+compile_error!("features `mainnet-beta` and `devnet` are mutually exclusive, build devnet with `--no-default-features --features devnet`");
+#[cfg(not(any(feature = "mainnet-beta", feature = "devnet")))]
+compile_error!(
+    "select the drift program cluster with feature `mainnet-beta` (default) or `devnet`"
+);
+
+pub mod abi;
+pub mod accounts;
+pub mod context;
 mod exports;
 pub mod margin;
+pub mod risk;
 pub mod types;
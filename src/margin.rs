@@ -2,10 +2,11 @@
 use std::cmp::Ordering;
 
 use drift_program::{
+    error::{DriftResult, ErrorCode},
     math::{
         constants::{
             MARGIN_PRECISION_I128, MARGIN_PRECISION_U128, OPEN_ORDER_MARGIN_REQUIREMENT,
-            QUOTE_SPOT_MARKET_INDEX,
+            QUOTE_SPOT_MARKET_INDEX, SPOT_WEIGHT_PRECISION_U128,
         },
         margin::{calculate_perp_position_value_and_pnl, MarginRequirementType},
         spot_balance::{get_strict_token_value, get_token_amount},
@@ -173,17 +174,36 @@ pub fn calculate_simplified_margin_requirement(
     market_state: &MarketState,
     margin_type: MarginRequirementType,
     margin_buffer: u32,
-) -> Result<SimplifiedMarginCalculation, drift_program::error::ErrorCode> {
+) -> DriftResult<SimplifiedMarginCalculation> {
+    calculate_simplified_margin_breakdown(user, market_state, margin_type, margin_buffer, false)
+        .map(|breakdown| breakdown.to_simplified())
+}
+
+// `calculate_simplified_margin_requirement` with strict oracle prices, spot and quote prices are
+// bounded by the market's 5min oracle TWAP (the lower for assets, the higher for liabilities) like
+// the program does for withdrawals
+pub fn calculate_strict_simplified_margin_requirement(
+    user: &User,
+    market_state: &MarketState,
+    margin_type: MarginRequirementType,
+    margin_buffer: u32,
+) -> DriftResult<SimplifiedMarginCalculation> {
+    calculate_simplified_margin_breakdown(user, market_state, margin_type, margin_buffer, true)
+        .map(|breakdown| breakdown.to_simplified())
+}
+
+// Per-position contributions of the simplified margin calculation, they sum to
+// `calculate_simplified_margin_requirement`
+pub fn calculate_simplified_margin_breakdown(
+    user: &User,
+    market_state: &MarketState,
+    margin_type: MarginRequirementType,
+    margin_buffer: u32,
+    strict: bool,
+) -> DriftResult<MarginBreakdown> {
     let user_high_leverage_mode = user.is_high_leverage_mode(margin_type);
-    let mut total_collateral = 0i128;
-    let mut total_collateral_buffer = 0i128;
-    let mut margin_requirement = 0u128;
-    let mut margin_requirement_plus_buffer = 0u128;
     let margin_buffer = margin_buffer as u128;
-
-    let mut isolated_margin_calculations = [IsolatedMarginCalculation::default(); 8];
-    let mut with_perp_isolated_liability = false;
-    let mut with_spot_isolated_liability = false;
+    let mut breakdown = MarginBreakdown::default();
 
     // Get user's custom margin ratio (only applied for initial margin)
     let user_custom_margin_ratio = if margin_type == MarginRequirementType::Initial {
@@ -193,262 +213,298 @@ pub fn calculate_simplified_margin_requirement(
     };
 
     // Process spot positions using worst-case fill simulation
-    for spot_position in &user.spot_positions {
+    for (spot_position, position_breakdown) in user
+        .spot_positions
+        .iter()
+        .zip(breakdown.spot_positions.iter_mut())
+    {
         if spot_position.is_available() {
             continue;
         }
+        *position_breakdown = calculate_simplified_spot_position_margin(
+            spot_position,
+            market_state,
+            margin_type,
+            user_custom_margin_ratio,
+            margin_buffer,
+            strict,
+            user.pool_id,
+        )?;
+    }
 
-        let spot_market = market_state.get_spot_market(spot_position.market_index);
-        let oracle = market_state
-            .get_spot_oracle_price(spot_position.market_index)
-            .ok_or(drift_program::error::ErrorCode::OracleNotFound)?;
+    for (perp_position, position_breakdown) in user
+        .perp_positions
+        .iter()
+        .zip(breakdown.perp_positions.iter_mut())
+    {
+        if perp_position.is_available() {
+            continue;
+        }
+        *position_breakdown = calculate_simplified_perp_position_margin(
+            perp_position,
+            market_state,
+            margin_type,
+            user_custom_margin_ratio,
+            user_high_leverage_mode,
+            margin_buffer,
+            strict,
+        )?;
+    }
 
-        let pyth = market_state.get_spot_pyth_price(spot_position.market_index);
+    Ok(breakdown)
+}
 
-        let oracle_price = match pyth {
-            Some(p) if p.price != 0 && oracle.price == 0 => p,
-            Some(p) if p.price != 0 && oracle.price != 0 => {
-                let diff_bps =
-                    (p.price.abs_diff(oracle.price) * 10_000) / oracle.price.unsigned_abs();
-                if diff_bps > market_state.pyth_oracle_diff_threshold_bps {
-                    p
-                } else {
-                    *oracle
+fn calculate_simplified_spot_position_margin(
+    spot_position: &SpotPosition,
+    market_state: &MarketState,
+    margin_type: MarginRequirementType,
+    user_custom_margin_ratio: u32,
+    margin_buffer: u128,
+    strict: bool,
+    user_pool_id: u8,
+) -> DriftResult<PositionMarginBreakdown> {
+    let spot_market = market_state.try_get_spot_market(spot_position.market_index)?;
+    let oracle_price = market_state
+        .get_spot_margin_price(spot_position.market_index)
+        .ok_or(ErrorCode::OracleNotFound)?;
+
+    let signed_token_amount = spot_position.get_signed_token_amount(spot_market)?;
+
+    let strict_oracle_price = StrictOraclePrice::new(
+        oracle_price.price,
+        spot_market
+            .historical_oracle_data
+            .last_oracle_price_twap_5min,
+        strict,
+    );
+
+    let mut breakdown = PositionMarginBreakdown {
+        market_index: spot_position.market_index,
+        token_amount: signed_token_amount,
+        ..Default::default()
+    };
+
+    // Check if position has open orders - if not, use simple calculation
+    if spot_market.market_index == QUOTE_SPOT_MARKET_INDEX {
+        // No open orders - use simple token value calculation
+        let token_value = get_strict_token_value(
+            signed_token_amount,
+            spot_market.decimals,
+            &strict_oracle_price,
+        )?;
+        breakdown.value = token_value;
+
+        match spot_position.balance_type {
+            SpotBalanceType::Deposit => {
+                // usdc deposit in pool 1 doesn't count
+                if user_pool_id != 1 {
+                    breakdown.weighted_value = token_value;
+                    breakdown.collateral = token_value;
                 }
             }
-            _ => *oracle,
-        };
-
-        let signed_token_amount = spot_position.get_signed_token_amount(spot_market)?;
-
-        let mut skip_token_value = false;
-        if !(user.pool_id == 1 && spot_market.market_index == 0 && !spot_position.is_borrow()) {
-        } else {
-            skip_token_value = true;
+            SpotBalanceType::Borrow => {
+                let liability_value = token_value.unsigned_abs();
+                breakdown.weighted_value = token_value;
+                breakdown.margin_requirement = liability_value;
+                breakdown.margin_requirement_plus_buffer =
+                    liability_value + (liability_value * margin_buffer) / MARGIN_PRECISION_U128;
+            }
         }
+    } else {
+        let OrderFillSimulation {
+            token_amount: _worst_case_token_amount,
+            orders_value: worst_case_orders_value,
+            token_value: worst_case_token_value,
+            weighted_token_value: worst_case_weighted_token_value,
+            ..
+        } = spot_position
+            .get_worst_case_fill_simulation(
+                spot_market,
+                &strict_oracle_price,
+                Some(signed_token_amount),
+                margin_type,
+            )?
+            .apply_user_custom_margin_ratio(
+                spot_market,
+                strict_oracle_price.current,
+                user_custom_margin_ratio,
+            )?;
+        breakdown.value = worst_case_token_value;
+        breakdown.weighted_value = worst_case_weighted_token_value;
+        breakdown.orders_value = worst_case_orders_value;
 
-        // Check if position has open orders - if not, use simple calculation
-        if spot_market.market_index == QUOTE_SPOT_MARKET_INDEX {
-            // No open orders - use simple token value calculation
-            let mut token_value = calculate_token_value(
-                signed_token_amount,
-                oracle_price.price,
-                spot_market.decimals,
-            );
+        // Add open order margin requirement
+        breakdown.open_order_margin = calculate_spot_open_order_margin(spot_position);
+        breakdown.margin_requirement += breakdown.open_order_margin;
 
-            match spot_position.balance_type {
-                SpotBalanceType::Deposit => {
-                    // usdc deposit in pool 1 doesn't count
-                    if skip_token_value {
-                        token_value = 0;
-                    }
-                    total_collateral += token_value;
-                }
-                SpotBalanceType::Borrow => {
-                    let liability_value = token_value.unsigned_abs();
-                    margin_requirement += liability_value;
-                    margin_requirement_plus_buffer +=
-                        liability_value + (liability_value * margin_buffer) / MARGIN_PRECISION_U128;
-                }
+        match worst_case_token_value.cmp(&0) {
+            Ordering::Greater => {
+                breakdown.collateral += worst_case_weighted_token_value;
             }
-        } else {
-            // in non-strict mode ignore twap
-            let strict_oracle_price = StrictOraclePrice {
-                current: oracle_price.price,
-                twap_5min: None,
-            };
-
-            let OrderFillSimulation {
-                token_amount: _worst_case_token_amount,
-                orders_value: worst_case_orders_value,
-                token_value: worst_case_token_value,
-                weighted_token_value: worst_case_weighted_token_value,
-                ..
-            } = spot_position
-                .get_worst_case_fill_simulation(
-                    spot_market,
-                    &strict_oracle_price,
-                    Some(signed_token_amount),
-                    margin_type,
-                )?
-                .apply_user_custom_margin_ratio(
-                    spot_market,
-                    strict_oracle_price.current,
-                    user_custom_margin_ratio,
-                )?;
-
-            // Add open order margin requirement
-            let open_order_margin = calculate_spot_open_order_margin(spot_position);
-            margin_requirement += open_order_margin;
-
-            match worst_case_token_value.cmp(&0) {
-                Ordering::Greater => {
-                    total_collateral += worst_case_weighted_token_value;
-                }
-                Ordering::Less => {
-                    let liability_value = worst_case_weighted_token_value.unsigned_abs();
-                    margin_requirement += liability_value;
-                    margin_requirement_plus_buffer += liability_value
-                        + (worst_case_token_value.unsigned_abs() * margin_buffer)
-                            / MARGIN_PRECISION_U128;
-
-                    if spot_market.asset_tier == AssetTier::Isolated {
-                        with_spot_isolated_liability = true;
-                    }
-                }
-                Ordering::Equal => {
-                    if spot_position.has_open_order()
-                        && spot_market.asset_tier == AssetTier::Isolated
-                    {
-                        with_spot_isolated_liability = true;
-                    }
+            Ordering::Less => {
+                let liability_value = worst_case_weighted_token_value.unsigned_abs();
+                breakdown.margin_requirement += liability_value;
+                breakdown.margin_requirement_plus_buffer += liability_value
+                    + (worst_case_token_value.unsigned_abs() * margin_buffer)
+                        / MARGIN_PRECISION_U128;
+
+                if spot_market.asset_tier == AssetTier::Isolated {
+                    breakdown.has_isolated_liability = true;
                 }
             }
-
-            match worst_case_orders_value.cmp(&0) {
-                Ordering::Greater => {
-                    total_collateral += worst_case_orders_value;
+            Ordering::Equal => {
+                if spot_position.has_open_order() && spot_market.asset_tier == AssetTier::Isolated {
+                    breakdown.has_isolated_liability = true;
                 }
-                Ordering::Less => {
-                    let liability_value = worst_case_orders_value.unsigned_abs();
-                    margin_requirement += liability_value;
-                    margin_requirement_plus_buffer +=
-                        liability_value + (liability_value * margin_buffer) / MARGIN_PRECISION_U128;
-                }
-                Ordering::Equal => {}
             }
-        };
-    }
-
-    for perp_position in &user.perp_positions {
-        if perp_position.is_available() {
-            continue;
         }
 
-        let perp_market = market_state.get_perp_market(perp_position.market_index);
-
-        let oracle = market_state
-            .get_perp_oracle_price(perp_position.market_index)
-            .ok_or(drift_program::error::ErrorCode::OracleNotFound)?;
-        let pyth = market_state.get_perp_pyth_price(perp_position.market_index);
-
-        let oracle_price = match pyth {
-            Some(p) if p.price != 0 && oracle.price == 0 => p,
-            Some(p) if p.price != 0 && oracle.price != 0 => {
-                let diff_bps =
-                    (p.price.abs_diff(oracle.price) * 10_000) / oracle.price.unsigned_abs();
-                if diff_bps > market_state.pyth_oracle_diff_threshold_bps {
-                    p
-                } else {
-                    *oracle
-                }
+        match worst_case_orders_value.cmp(&0) {
+            Ordering::Greater => {
+                breakdown.collateral += worst_case_orders_value;
             }
-            _ => *oracle,
-        };
-
-        let strict_quote_price = {
-            let quote_price_data = market_state
-                .get_spot_oracle_price(perp_market.quote_spot_market_index)
-                .ok_or(drift_program::error::ErrorCode::OracleNotFound)?;
-            StrictOraclePrice {
-                current: quote_price_data.price,
-                twap_5min: None,
+            Ordering::Less => {
+                let liability_value = worst_case_orders_value.unsigned_abs();
+                breakdown.margin_requirement += liability_value;
+                breakdown.margin_requirement_plus_buffer +=
+                    liability_value + (liability_value * margin_buffer) / MARGIN_PRECISION_U128;
             }
-        };
-
-        let perp_position_custom_margin_ratio = if margin_type == MarginRequirementType::Initial {
-            perp_position.max_margin_ratio as u32
-        } else {
-            0_u32
-        };
-
-        // Calculate unrealized PnL
-        let (perp_margin_requirement, weighted_pnl, worst_case_liability_value, _base_asset_value) =
-            calculate_perp_position_value_and_pnl(
-                perp_position,
-                perp_market,
-                &oracle_price,
-                &strict_quote_price,
-                margin_type,
-                user_custom_margin_ratio.max(perp_position_custom_margin_ratio),
-                user_high_leverage_mode,
-            )?;
+            Ordering::Equal => {}
+        }
+    }
 
-        if perp_position.is_isolated() {
-            let quote_spot_market =
-                market_state.get_spot_market(perp_market.quote_spot_market_index);
+    breakdown.oracle_price = spot_valuation_price(&strict_oracle_price, breakdown.value);
+    breakdown.weight = effective_weight(
+        breakdown.value,
+        breakdown.weighted_value,
+        SPOT_WEIGHT_PRECISION_U128,
+    );
 
-            let quote_token_amount = get_token_amount(
-                perp_position.isolated_position_scaled_balance as u128,
-                quote_spot_market,
-                &SpotBalanceType::Deposit,
-            )?;
+    Ok(breakdown)
+}
 
-            let quote_token_value = get_strict_token_value(
-                quote_token_amount as i128,
-                quote_spot_market.decimals,
-                &strict_quote_price,
-            )?;
+fn calculate_simplified_perp_position_margin(
+    perp_position: &PerpPosition,
+    market_state: &MarketState,
+    margin_type: MarginRequirementType,
+    user_custom_margin_ratio: u32,
+    user_high_leverage_mode: bool,
+    margin_buffer: u128,
+    strict: bool,
+) -> DriftResult<PositionMarginBreakdown> {
+    let perp_market = market_state.try_get_perp_market(perp_position.market_index)?;
+    let oracle_price = market_state
+        .get_perp_margin_price(perp_position.market_index)
+        .ok_or(ErrorCode::OracleNotFound)?;
+
+    // the quote spot market is only needed for its twap in strict mode and for isolated collateral
+    let quote_spot_market = if strict || perp_position.is_isolated() {
+        Some(market_state.try_get_spot_market(perp_market.quote_spot_market_index)?)
+    } else {
+        None
+    };
+    let strict_quote_price = {
+        let quote_price_data = market_state
+            .get_spot_oracle_price(perp_market.quote_spot_market_index)
+            .ok_or(ErrorCode::OracleNotFound)?;
+        match quote_spot_market {
+            Some(quote_spot_market) => StrictOraclePrice::new(
+                quote_price_data.price,
+                quote_spot_market
+                    .historical_oracle_data
+                    .last_oracle_price_twap_5min,
+                strict,
+            ),
+            None => StrictOraclePrice {
+                current: quote_price_data.price,
+                twap_5min: None,
+            },
+        }
+    };
 
-            let iso_total_collateral = quote_token_value + weighted_pnl;
+    let perp_position_custom_margin_ratio = if margin_type == MarginRequirementType::Initial {
+        perp_position.max_margin_ratio as u32
+    } else {
+        0_u32
+    };
 
-            let iso_total_collateral_buffer = if margin_buffer > 0 && weighted_pnl < 0 {
-                (weighted_pnl * margin_buffer as i128) / MARGIN_PRECISION_I128
-            } else {
-                0
-            };
+    // Calculate unrealized PnL
+    let (perp_margin_requirement, weighted_pnl, worst_case_liability_value, _base_asset_value) =
+        calculate_perp_position_value_and_pnl(
+            perp_position,
+            perp_market,
+            &oracle_price,
+            &strict_quote_price,
+            margin_type,
+            user_custom_margin_ratio.max(perp_position_custom_margin_ratio),
+            user_high_leverage_mode,
+        )?;
 
-            let iso_margin_requirement_plus_buffer = if margin_buffer > 0 {
-                perp_margin_requirement
-                    + (worst_case_liability_value * margin_buffer) / MARGIN_PRECISION_U128
-            } else {
-                0
-            };
+    let open_order_margin = calculate_perp_open_order_margin(perp_position);
+    let mut breakdown = PositionMarginBreakdown {
+        market_index: perp_position.market_index,
+        token_amount: perp_position.base_asset_amount as i128,
+        value: worst_case_liability_value as i128,
+        weighted_value: perp_margin_requirement.saturating_sub(open_order_margin) as i128,
+        unrealized_pnl: weighted_pnl,
+        open_order_margin,
+        margin_requirement: perp_margin_requirement,
+        oracle_price: oracle_price.price,
+        is_isolated: perp_position.is_isolated(),
+        ..Default::default()
+    };
+    breakdown.weight = effective_weight(
+        breakdown.value,
+        breakdown.weighted_value,
+        MARGIN_PRECISION_U128,
+    );
+
+    if let Some(quote_spot_market) = quote_spot_market.filter(|_| breakdown.is_isolated) {
+        let quote_token_amount = get_token_amount(
+            perp_position.isolated_position_scaled_balance as u128,
+            quote_spot_market,
+            &SpotBalanceType::Deposit,
+        )?;
+
+        let quote_token_value = get_strict_token_value(
+            quote_token_amount as i128,
+            quote_spot_market.decimals,
+            &strict_quote_price,
+        )?;
 
-            if let Some(slot) = isolated_margin_calculations
-                .iter_mut()
-                .find(|c| c.is_empty())
-            {
-                *slot = IsolatedMarginCalculation {
-                    market_index: perp_position.market_index,
-                    margin_requirement: perp_margin_requirement,
-                    total_collateral: iso_total_collateral,
-                    total_collateral_buffer: iso_total_collateral_buffer,
-                    margin_requirement_plus_buffer: iso_margin_requirement_plus_buffer,
-                };
-            }
+        breakdown.collateral = quote_token_value + weighted_pnl;
 
-            with_perp_isolated_liability = true;
+        breakdown.collateral_buffer = if margin_buffer > 0 && weighted_pnl < 0 {
+            (weighted_pnl * margin_buffer as i128) / MARGIN_PRECISION_I128
         } else {
-            margin_requirement += perp_margin_requirement;
-            margin_requirement_plus_buffer += perp_margin_requirement
-                + (worst_case_liability_value * margin_buffer) / MARGIN_PRECISION_U128;
-
-            total_collateral += weighted_pnl;
-            if weighted_pnl < 0 {
-                total_collateral_buffer +=
-                    (weighted_pnl * margin_buffer as i128) / MARGIN_PRECISION_I128;
-            }
-        }
+            0
+        };
 
-        let has_perp_liability = perp_position.base_asset_amount != 0
-            || perp_position.quote_asset_amount < 0
-            || perp_position.has_open_order();
+        breakdown.margin_requirement_plus_buffer = if margin_buffer > 0 {
+            perp_margin_requirement
+                + (worst_case_liability_value * margin_buffer) / MARGIN_PRECISION_U128
+        } else {
+            0
+        };
+    } else {
+        breakdown.margin_requirement_plus_buffer = perp_margin_requirement
+            + (worst_case_liability_value * margin_buffer) / MARGIN_PRECISION_U128;
 
-        if has_perp_liability && perp_market.contract_tier == ContractTier::Isolated {
-            with_perp_isolated_liability = true;
+        breakdown.collateral = weighted_pnl;
+        if weighted_pnl < 0 {
+            breakdown.collateral_buffer =
+                (weighted_pnl * margin_buffer as i128) / MARGIN_PRECISION_I128;
         }
     }
 
-    Ok(SimplifiedMarginCalculation {
-        total_collateral,
-        margin_requirement,
-        total_collateral_buffer,
-        margin_requirement_plus_buffer,
-        isolated_margin_calculations,
-        with_perp_isolated_liability,
-        with_spot_isolated_liability,
-    })
+    let has_perp_liability = perp_position.base_asset_amount != 0
+        || perp_position.quote_asset_amount < 0
+        || perp_position.has_open_order();
+
+    breakdown.has_isolated_liability = breakdown.is_isolated
+        || (has_perp_liability && perp_market.contract_tier == ContractTier::Isolated);
+
+    Ok(breakdown)
 }
 
 /// Incremental margin calculation
@@ -483,12 +539,156 @@ pub struct PositionCollateral {
     pub liability_buffer: u128,
     pub last_updated: u64,
     pub market_index: u16,
+    /// isolated perp position, margined separately from the cross totals
+    pub is_isolated: bool,
+    /// isolated position or liability in an isolated tier market
+    pub has_isolated_liability: bool,
+}
+
+/// Margin contribution of a single position with the values it is computed from
+///
+/// `collateral`, `margin_requirement` and the buffers are what the position adds to the cross
+/// totals, or to its own isolated bucket when `is_isolated`
+#[repr(C, align(16))]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PositionMarginBreakdown {
+    /// spot: signed token amount, perp: base asset amount
+    pub token_amount: i128,
+    /// Unweighted value `weight` applies to, spot: worst case token value (negative for
+    /// liabilities), perp: worst case liability value
+    pub value: i128,
+    /// spot: worst case weighted token value, perp: margin requirement of `value`
+    pub weighted_value: i128,
+    /// spot: worst case value of open orders
+    pub orders_value: i128,
+    /// perp: weighted unrealized pnl, counted as collateral
+    pub unrealized_pnl: i128,
+    pub open_order_margin: u128,
+    pub collateral: i128,
+    pub collateral_buffer: i128,
+    pub margin_requirement: u128,
+    pub margin_requirement_plus_buffer: u128,
+    /// Oracle price the position is valued at, after any pyth override and strict TWAP bound
+    pub oracle_price: i64,
+    /// Effective weight of `weighted_value` over `value`, spot: asset/liability weight
+    /// (`SPOT_WEIGHT_PRECISION`), perp: margin ratio (`MARGIN_PRECISION`)
+    pub weight: u32,
+    pub market_index: u16,
+    /// isolated perp position, margined separately from the cross totals
+    pub is_isolated: bool,
+    /// isolated position or liability in an isolated tier market
+    pub has_isolated_liability: bool,
+}
+
+/// Margin contribution of every position of a user
+///
+/// Indexed like `User::spot_positions`/`User::perp_positions`, available positions are zeroed
+#[repr(C, align(16))]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct MarginBreakdown {
+    pub spot_positions: [PositionMarginBreakdown; 8],
+    pub perp_positions: [PositionMarginBreakdown; 8],
+}
+
+impl MarginBreakdown {
+    /// Sum the positions into totals and isolated buckets
+    pub(crate) fn to_simplified(&self) -> SimplifiedMarginCalculation {
+        let mut calculation = SimplifiedMarginCalculation {
+            total_collateral: 0,
+            total_collateral_buffer: 0,
+            margin_requirement: 0,
+            margin_requirement_plus_buffer: 0,
+            isolated_margin_calculations: [IsolatedMarginCalculation::default(); 8],
+            with_perp_isolated_liability: false,
+            with_spot_isolated_liability: false,
+        };
+
+        for position in &self.spot_positions {
+            calculation.total_collateral += position.collateral;
+            calculation.total_collateral_buffer += position.collateral_buffer;
+            calculation.margin_requirement += position.margin_requirement;
+            calculation.margin_requirement_plus_buffer += position.margin_requirement_plus_buffer;
+            calculation.with_spot_isolated_liability |= position.has_isolated_liability;
+        }
+
+        for position in &self.perp_positions {
+            if position.is_isolated {
+                if let Some(slot) = calculation
+                    .isolated_margin_calculations
+                    .iter_mut()
+                    .find(|c| c.is_empty())
+                {
+                    *slot = IsolatedMarginCalculation {
+                        market_index: position.market_index,
+                        margin_requirement: position.margin_requirement,
+                        total_collateral: position.collateral,
+                        total_collateral_buffer: position.collateral_buffer,
+                        margin_requirement_plus_buffer: position.margin_requirement_plus_buffer,
+                    };
+                }
+            } else {
+                calculation.total_collateral += position.collateral;
+                calculation.total_collateral_buffer += position.collateral_buffer;
+                calculation.margin_requirement += position.margin_requirement;
+                calculation.margin_requirement_plus_buffer +=
+                    position.margin_requirement_plus_buffer;
+            }
+            calculation.with_perp_isolated_liability |= position.has_isolated_liability;
+        }
+
+        calculation
+    }
+}
+
+/// Weight applied to `value` to get `weighted_value`, rounded up to undo the floor of the
+/// weighting. 0 without a value
+fn effective_weight(value: i128, weighted_value: i128, precision: u128) -> u32 {
+    if value == 0 {
+        return 0;
+    }
+    (weighted_value.unsigned_abs() * precision)
+        .div_ceil(value.unsigned_abs())
+        .try_into()
+        .unwrap_or(u32::MAX)
+}
+
+/// Oracle price a spot `value` is valued at, the strict bound is the lower for assets and the
+/// higher for liabilities
+fn spot_valuation_price(strict_oracle_price: &StrictOraclePrice, value: i128) -> i64 {
+    if value < 0 {
+        strict_oracle_price.max()
+    } else {
+        strict_oracle_price.min()
+    }
 }
 
 impl PositionCollateral {
+    fn from_breakdown(breakdown: &PositionMarginBreakdown, timestamp: u64) -> Self {
+        Self {
+            market_index: breakdown.market_index,
+            collateral_value: breakdown.collateral,
+            collateral_buffer: breakdown.collateral_buffer,
+            liability_value: breakdown.margin_requirement,
+            liability_buffer: breakdown.margin_requirement_plus_buffer,
+            last_updated: timestamp,
+            is_isolated: breakdown.is_isolated,
+            has_isolated_liability: breakdown.has_isolated_liability,
+        }
+    }
+
     fn exists(&self) -> bool {
         self.liability_value != 0 || self.collateral_value != 0 || self.last_updated > 0
     }
+
+    fn to_isolated_margin_calculation(self) -> IsolatedMarginCalculation {
+        IsolatedMarginCalculation {
+            market_index: self.market_index,
+            margin_requirement: self.liability_value,
+            total_collateral: self.collateral_value,
+            total_collateral_buffer: self.collateral_buffer,
+            margin_requirement_plus_buffer: self.liability_buffer,
+        }
+    }
 }
 
 impl Default for IncrementalMarginCalculation {
@@ -537,6 +737,17 @@ impl IncrementalMarginCalculation {
         timestamp: u64,
         margin_buffer: u32,
     ) -> Self {
+        Self::try_from_user(user, market_state, margin_type, timestamp, margin_buffer).unwrap()
+    }
+
+    // Like `from_user` but returns any missing market or math error instead of panicking
+    pub fn try_from_user(
+        user: &User,
+        market_state: &MarketState,
+        margin_type: MarginRequirementType,
+        timestamp: u64,
+        margin_buffer: u32,
+    ) -> DriftResult<Self> {
         let user_high_leverage_mode = user.is_high_leverage_mode(margin_type);
         let user_custom_margin_ratio = if margin_type == MarginRequirementType::Initial {
             user.max_margin_ratio
@@ -550,8 +761,8 @@ impl IncrementalMarginCalculation {
             margin_buffer,
             user.pool_id,
         );
-        this.calculate(user, market_state, timestamp);
-        this
+        this.try_calculate(user, market_state, timestamp)?;
+        Ok(this)
     }
 
     pub fn free_collateral(&self) -> i128 {
@@ -577,6 +788,16 @@ impl IncrementalMarginCalculation {
 
     // Calculate full margin info
     pub fn calculate(&mut self, user: &User, market_state: &MarketState, timestamp: u64) {
+        self.try_calculate(user, market_state, timestamp).unwrap()
+    }
+
+    // Like `calculate` but returns any missing market or math error instead of panicking
+    pub fn try_calculate(
+        &mut self,
+        user: &User,
+        market_state: &MarketState,
+        timestamp: u64,
+    ) -> DriftResult {
         // Reset totals
         self.total_collateral = 0;
         self.margin_requirement = 0;
@@ -588,18 +809,20 @@ impl IncrementalMarginCalculation {
         // Recalculate all spot positions
         for spot_position in &user.spot_positions {
             if !spot_position.is_available() {
-                self.update_spot_position(spot_position, market_state, timestamp);
+                self.try_update_spot_position(spot_position, market_state, timestamp)?;
             }
         }
 
         // Recalculate all perp positions
         for perp_position in &user.perp_positions {
             if !perp_position.is_available() {
-                self.update_perp_position(perp_position, market_state, timestamp);
+                self.try_update_perp_position(perp_position, market_state, timestamp)?;
             }
         }
 
         self.last_updated = timestamp;
+
+        Ok(())
     }
 
     // Update a single spot position and recalculate totals
@@ -609,6 +832,17 @@ impl IncrementalMarginCalculation {
         market_state: &MarketState,
         timestamp: u64,
     ) {
+        self.try_update_spot_position(spot_position, market_state, timestamp)
+            .unwrap()
+    }
+
+    // Like `update_spot_position` but returns any missing market or math error instead of panicking
+    pub fn try_update_spot_position(
+        &mut self,
+        spot_position: &SpotPosition,
+        market_state: &MarketState,
+        timestamp: u64,
+    ) -> DriftResult {
         // Find existing position
         if let Some(pos) = self
             .spot_collateral
@@ -624,7 +858,7 @@ impl IncrementalMarginCalculation {
                 self.margin_buffer,
                 timestamp,
                 self.user_pool_id,
-            ) {
+            )? {
                 // Update the existing position in place
                 let old_collateral = &self.spot_collateral[pos];
 
@@ -654,13 +888,12 @@ impl IncrementalMarginCalculation {
                 self.margin_buffer,
                 timestamp,
                 self.user_pool_id,
-            ) {
+            )? {
                 // Add new contribution
                 self.total_collateral += new_collateral.collateral_value;
                 self.margin_requirement += new_collateral.liability_value;
                 self.total_collateral_buffer += new_collateral.collateral_buffer;
-                self.margin_requirement_plus_buffer +=
-                    new_collateral.liability_value + new_collateral.liability_buffer;
+                self.margin_requirement_plus_buffer += new_collateral.liability_buffer;
 
                 // insert position
                 if let Some(idx) = self.spot_collateral.iter().position(|x| {
@@ -672,6 +905,8 @@ impl IncrementalMarginCalculation {
         }
 
         self.last_updated = timestamp;
+
+        Ok(())
     }
 
     // Update a single perp position and recalculate totals
@@ -681,6 +916,17 @@ impl IncrementalMarginCalculation {
         market_state: &MarketState,
         timestamp: u64,
     ) {
+        self.try_update_perp_position(perp_position, market_state, timestamp)
+            .unwrap()
+    }
+
+    // Like `update_perp_position` but returns any missing market or math error instead of panicking
+    pub fn try_update_perp_position(
+        &mut self,
+        perp_position: &PerpPosition,
+        market_state: &MarketState,
+        timestamp: u64,
+    ) -> DriftResult {
         // Find existing position
         if let Some(pos) = self
             .perp_collateral
@@ -688,29 +934,24 @@ impl IncrementalMarginCalculation {
             .position(|c| c.market_index == perp_position.market_index && c.exists())
         {
             // Remove old contribution
-            let old_collateral = &self.perp_collateral[pos];
+            let old_collateral = self.perp_collateral[pos];
             // Calculate new contribution and mutate in place
             if let Some(new_collateral) = calculate_perp_position_collateral(
                 perp_position,
                 market_state,
                 self.margin_type,
+                self.user_custom_margin_ratio,
                 self.user_high_leverage_mode,
                 self.margin_buffer,
                 timestamp,
-            ) {
-                self.total_collateral -= old_collateral.collateral_value;
-                self.margin_requirement -= old_collateral.liability_value;
-                self.total_collateral_buffer -= old_collateral.collateral_buffer;
-                self.margin_requirement_plus_buffer -= old_collateral.liability_buffer;
+            )? {
+                self.remove_cross_perp_collateral(&old_collateral);
 
                 if perp_position.is_available() {
                     // removed
                     self.perp_collateral[pos] = Default::default();
                 } else {
-                    self.total_collateral += new_collateral.collateral_value;
-                    self.margin_requirement += new_collateral.liability_value;
-                    self.total_collateral_buffer += new_collateral.collateral_buffer;
-                    self.margin_requirement_plus_buffer += new_collateral.liability_buffer;
+                    self.add_cross_perp_collateral(&new_collateral);
                     self.perp_collateral[pos] = new_collateral;
                 }
             }
@@ -720,15 +961,13 @@ impl IncrementalMarginCalculation {
                 perp_position,
                 market_state,
                 self.margin_type,
+                self.user_custom_margin_ratio,
                 self.user_high_leverage_mode,
                 self.margin_buffer,
                 timestamp,
-            ) {
+            )? {
                 // Add new contribution
-                self.total_collateral += new_collateral.collateral_value;
-                self.margin_requirement += new_collateral.liability_value;
-                self.total_collateral_buffer += new_collateral.collateral_buffer;
-                self.margin_requirement_plus_buffer += new_collateral.liability_buffer;
+                self.add_cross_perp_collateral(&new_collateral);
 
                 // insert position
                 if let Some(idx) = self.perp_collateral.iter().position(|x| {
@@ -740,35 +979,128 @@ impl IncrementalMarginCalculation {
         }
 
         self.last_updated = timestamp;
+
+        Ok(())
+    }
+
+    // Isolated perp positions are tracked in their own buckets, not the cross totals
+    fn add_cross_perp_collateral(&mut self, collateral: &PositionCollateral) {
+        if !collateral.is_isolated {
+            self.total_collateral += collateral.collateral_value;
+            self.margin_requirement += collateral.liability_value;
+            self.total_collateral_buffer += collateral.collateral_buffer;
+            self.margin_requirement_plus_buffer += collateral.liability_buffer;
+        }
+    }
+
+    fn remove_cross_perp_collateral(&mut self, collateral: &PositionCollateral) {
+        if !collateral.is_isolated {
+            self.total_collateral -= collateral.collateral_value;
+            self.margin_requirement -= collateral.liability_value;
+            self.total_collateral_buffer -= collateral.collateral_buffer;
+            self.margin_requirement_plus_buffer -= collateral.liability_buffer;
+        }
     }
 
     // Convert to simplified calculation for compatibility
     pub fn to_simplified(&self) -> SimplifiedMarginCalculation {
+        let mut isolated_margin_calculations = [IsolatedMarginCalculation::default(); 8];
+        for (slot, collateral) in isolated_margin_calculations.iter_mut().zip(
+            self.perp_collateral
+                .iter()
+                .filter(|c| c.is_isolated && c.exists()),
+        ) {
+            *slot = collateral.to_isolated_margin_calculation();
+        }
+
         SimplifiedMarginCalculation {
             total_collateral: self.total_collateral,
             margin_requirement: self.margin_requirement,
             total_collateral_buffer: self.total_collateral_buffer,
             margin_requirement_plus_buffer: self.margin_requirement_plus_buffer,
-            isolated_margin_calculations: [Default::default(); 8],
-            with_perp_isolated_liability: false,
-            with_spot_isolated_liability: false,
+            isolated_margin_calculations,
+            with_perp_isolated_liability: self
+                .perp_collateral
+                .iter()
+                .any(|c| c.has_isolated_liability),
+            with_spot_isolated_liability: self
+                .spot_collateral
+                .iter()
+                .any(|c| c.has_isolated_liability),
+        }
+    }
+
+    // Per-position contributions of `user` with this calculation's settings, recomputed from
+    // `market_state`. Positions without an oracle price are left zeroed as they are left out of
+    // the totals
+    pub fn breakdown(
+        &self,
+        user: &User,
+        market_state: &MarketState,
+    ) -> DriftResult<MarginBreakdown> {
+        let mut breakdown = MarginBreakdown::default();
+
+        for (spot_position, position_breakdown) in user
+            .spot_positions
+            .iter()
+            .zip(breakdown.spot_positions.iter_mut())
+        {
+            if spot_position.is_available() {
+                continue;
+            }
+            if let Some(spot_breakdown) = calculate_spot_position_breakdown(
+                spot_position,
+                market_state,
+                self.margin_type,
+                self.user_custom_margin_ratio,
+                self.margin_buffer,
+                self.user_pool_id,
+            )? {
+                *position_breakdown = spot_breakdown;
+            }
+        }
+
+        for (perp_position, position_breakdown) in user
+            .perp_positions
+            .iter()
+            .zip(breakdown.perp_positions.iter_mut())
+        {
+            if perp_position.is_available() {
+                continue;
+            }
+            if let Some(perp_breakdown) = calculate_perp_position_breakdown(
+                perp_position,
+                market_state,
+                self.margin_type,
+                self.user_custom_margin_ratio,
+                self.user_high_leverage_mode,
+                self.margin_buffer,
+            )? {
+                *position_breakdown = perp_breakdown;
+            }
         }
+
+        Ok(breakdown)
     }
 }
 
 // Helper functions using existing Drift math utilities
-fn calculate_token_value(token_amount: i128, price: i64, decimals: u32) -> i128 {
+fn calculate_token_value(token_amount: i128, price: i64, decimals: u32) -> DriftResult<i128> {
     let strict_price = StrictOraclePrice {
         current: price,
         twap_5min: None,
     };
-    get_strict_token_value(token_amount, decimals, &strict_price).unwrap()
+    get_strict_token_value(token_amount, decimals, &strict_price)
 }
 
 fn calculate_spot_open_order_margin(position: &SpotPosition) -> u128 {
     (position.open_orders as u128) * OPEN_ORDER_MARGIN_REQUIREMENT
 }
 
+fn calculate_perp_open_order_margin(position: &PerpPosition) -> u128 {
+    (position.open_orders as u128) * OPEN_ORDER_MARGIN_REQUIREMENT
+}
+
 // Helper functions for incremental calculations
 fn calculate_spot_position_collateral(
     spot_position: &SpotPosition,
@@ -778,10 +1110,31 @@ fn calculate_spot_position_collateral(
     margin_buffer: u32,
     timestamp: u64,
     user_pool_id: u8,
-) -> Option<PositionCollateral> {
+) -> DriftResult<Option<PositionCollateral>> {
+    Ok(calculate_spot_position_breakdown(
+        spot_position,
+        market_state,
+        margin_type,
+        user_custom_margin_ratio,
+        margin_buffer,
+        user_pool_id,
+    )?
+    .map(|breakdown| PositionCollateral::from_breakdown(&breakdown, timestamp)))
+}
+
+fn calculate_spot_position_breakdown(
+    spot_position: &SpotPosition,
+    market_state: &MarketState,
+    margin_type: MarginRequirementType,
+    user_custom_margin_ratio: u32,
+    margin_buffer: u32,
+    user_pool_id: u8,
+) -> DriftResult<Option<PositionMarginBreakdown>> {
     let margin_buffer = margin_buffer as u128;
-    let spot_market = market_state.get_spot_market(spot_position.market_index);
-    let oracle_price = market_state.get_spot_oracle_price(spot_position.market_index)?;
+    let spot_market = market_state.try_get_spot_market(spot_position.market_index)?;
+    let Some(oracle_price) = market_state.get_spot_margin_price(spot_position.market_index) else {
+        return Ok(None);
+    };
 
     // Create strict oracle price for worst-case simulation
     // in non-strict mode ignore twap (same as simplified calculation)
@@ -791,7 +1144,7 @@ fn calculate_spot_position_collateral(
     };
 
     // Get signed token amount
-    let signed_token_amount = spot_position.get_signed_token_amount(spot_market).unwrap();
+    let signed_token_amount = spot_position.get_signed_token_amount(spot_market)?;
 
     // Check if position has open orders - if not, use simple calculation
     let (worst_case_token_value, worst_case_weighted_token_value, worst_case_orders_value) =
@@ -800,12 +1153,12 @@ fn calculate_spot_position_collateral(
                 signed_token_amount,
                 oracle_price.price,
                 spot_market.decimals,
-            );
+            )?;
             if !(user_pool_id == 1 && !spot_position.is_borrow()) {
                 (token_value, token_value, 0)
             } else {
                 // usdc deposit in pool 1 doesn't count
-                (0, 0, 0)
+                (token_value, 0, 0)
             }
         } else {
             // non-usdc spot position
@@ -821,14 +1174,12 @@ fn calculate_spot_position_collateral(
                     &strict_oracle_price,
                     Some(signed_token_amount),
                     margin_type,
-                )
-                .unwrap()
+                )?
                 .apply_user_custom_margin_ratio(
                     spot_market,
                     strict_oracle_price.current,
                     user_custom_margin_ratio,
-                )
-                .unwrap();
+                )?;
 
             (
                 worst_case_token_value,
@@ -849,7 +1200,9 @@ fn calculate_spot_position_collateral(
         Ordering::Less => {
             let liability = worst_case_weighted_token_value.unsigned_abs();
             liability_value += liability;
-            liability_buffer += liability + (liability * margin_buffer) / MARGIN_PRECISION_U128;
+            // buffer is on the unweighted liability, same as simplified calculation
+            liability_buffer += liability
+                + (worst_case_token_value.unsigned_abs() * margin_buffer) / MARGIN_PRECISION_U128;
         }
         Ordering::Equal => {}
     }
@@ -869,70 +1222,159 @@ fn calculate_spot_position_collateral(
     let open_order_margin = calculate_spot_open_order_margin(spot_position);
     liability_value += open_order_margin;
 
-    Some(PositionCollateral {
+    let has_isolated_liability = spot_market.market_index != QUOTE_SPOT_MARKET_INDEX
+        && spot_market.asset_tier == AssetTier::Isolated
+        && (worst_case_token_value < 0
+            || (worst_case_token_value == 0 && spot_position.has_open_order()));
+
+    Ok(Some(PositionMarginBreakdown {
         market_index: spot_position.market_index,
-        collateral_value,
-        collateral_buffer: 0,
-        liability_value,
-        liability_buffer,
-        last_updated: timestamp,
-    })
+        token_amount: signed_token_amount,
+        value: worst_case_token_value,
+        weighted_value: worst_case_weighted_token_value,
+        orders_value: worst_case_orders_value,
+        open_order_margin,
+        collateral: collateral_value,
+        margin_requirement: liability_value,
+        margin_requirement_plus_buffer: liability_buffer,
+        oracle_price: spot_valuation_price(&strict_oracle_price, worst_case_token_value),
+        weight: effective_weight(
+            worst_case_token_value,
+            worst_case_weighted_token_value,
+            SPOT_WEIGHT_PRECISION_U128,
+        ),
+        has_isolated_liability,
+        ..Default::default()
+    }))
 }
 
 fn calculate_perp_position_collateral(
     perp_position: &PerpPosition,
     market_state: &MarketState,
     margin_type: MarginRequirementType,
+    user_custom_margin_ratio: u32,
     user_high_leverage_mode: bool,
     margin_buffer: u32,
     timestamp: u64,
-) -> Option<PositionCollateral> {
-    let perp_market = market_state.get_perp_market(perp_position.market_index);
-    let oracle_price = market_state.get_perp_oracle_price(perp_position.market_index)?;
+) -> DriftResult<Option<PositionCollateral>> {
+    Ok(calculate_perp_position_breakdown(
+        perp_position,
+        market_state,
+        margin_type,
+        user_custom_margin_ratio,
+        user_high_leverage_mode,
+        margin_buffer,
+    )?
+    .map(|breakdown| PositionCollateral::from_breakdown(&breakdown, timestamp)))
+}
+
+fn calculate_perp_position_breakdown(
+    perp_position: &PerpPosition,
+    market_state: &MarketState,
+    margin_type: MarginRequirementType,
+    user_custom_margin_ratio: u32,
+    user_high_leverage_mode: bool,
+    margin_buffer: u32,
+) -> DriftResult<Option<PositionMarginBreakdown>> {
+    let perp_market = market_state.try_get_perp_market(perp_position.market_index)?;
+    let Some(oracle_price) = market_state.get_perp_margin_price(perp_position.market_index) else {
+        return Ok(None);
+    };
 
     // Get quote price for the perp market
-    let quote_oracle_data =
-        market_state.get_spot_oracle_price(perp_market.quote_spot_market_index)?;
+    let Some(quote_oracle_data) =
+        market_state.get_spot_oracle_price(perp_market.quote_spot_market_index)
+    else {
+        return Ok(None);
+    };
     let strict_quote_price = StrictOraclePrice {
         current: quote_oracle_data.price,
         twap_5min: None,
     };
 
+    // Custom margin ratios only apply to initial margin (same as simplified version)
+    let custom_margin_ratio = if margin_type == MarginRequirementType::Initial {
+        user_custom_margin_ratio.max(perp_position.max_margin_ratio as u32)
+    } else {
+        0_u32
+    };
+
     // Use the same calculation as simplified version
     let (perp_margin_requirement, weighted_pnl, worst_case_liability_value, _base_asset_value) =
         calculate_perp_position_value_and_pnl(
             perp_position,
             perp_market,
-            oracle_price,
+            &oracle_price,
             &strict_quote_price,
             margin_type,
-            0, // user_custom_margin_ratio - not used in cached version
+            custom_margin_ratio,
             user_high_leverage_mode,
-        )
-        .unwrap();
+        )?;
 
     // Calculate margin buffer
     let mut collateral_buffer = 0i128;
-    let collateral_value = weighted_pnl;
+    let mut collateral_value = weighted_pnl;
     let liability_value = perp_margin_requirement;
 
     // Apply buffer to margin requirement
-    let liability_buffer = liability_value
+    let mut liability_buffer = liability_value
         + (worst_case_liability_value * margin_buffer as u128) / MARGIN_PRECISION_U128;
 
     // Apply buffer to negative PnL (when it reduces collateral)
     if weighted_pnl < 0 {
-        collateral_buffer = (collateral_value * margin_buffer as i128) / MARGIN_PRECISION_I128;
+        collateral_buffer = (weighted_pnl * margin_buffer as i128) / MARGIN_PRECISION_I128;
+    }
+
+    let is_isolated = perp_position.is_isolated();
+    if is_isolated {
+        // isolated collateral is the position's own quote deposit plus its pnl
+        let quote_spot_market =
+            market_state.try_get_spot_market(perp_market.quote_spot_market_index)?;
+        let quote_token_amount = get_token_amount(
+            perp_position.isolated_position_scaled_balance as u128,
+            quote_spot_market,
+            &SpotBalanceType::Deposit,
+        )?;
+        collateral_value += get_strict_token_value(
+            quote_token_amount as i128,
+            quote_spot_market.decimals,
+            &strict_quote_price,
+        )?;
+        // same as simplified calculation, no buffered requirement without a buffer
+        if margin_buffer == 0 {
+            liability_buffer = 0;
+        }
     }
 
-    Some(PositionCollateral {
+    let has_perp_liability = perp_position.base_asset_amount != 0
+        || perp_position.quote_asset_amount < 0
+        || perp_position.has_open_order();
+
+    let open_order_margin = calculate_perp_open_order_margin(perp_position);
+    let weighted_value = perp_margin_requirement.saturating_sub(open_order_margin) as i128;
+
+    Ok(Some(PositionMarginBreakdown {
         market_index: perp_position.market_index,
-        collateral_value,
+        token_amount: perp_position.base_asset_amount as i128,
+        value: worst_case_liability_value as i128,
+        weighted_value,
+        unrealized_pnl: weighted_pnl,
+        open_order_margin,
+        collateral: collateral_value,
         collateral_buffer,
-        liability_value,
-        liability_buffer,
-        last_updated: timestamp,
-    })
+        margin_requirement: liability_value,
+        margin_requirement_plus_buffer: liability_buffer,
+        oracle_price: oracle_price.price,
+        weight: effective_weight(
+            worst_case_liability_value as i128,
+            weighted_value,
+            MARGIN_PRECISION_U128,
+        ),
+        is_isolated,
+        has_isolated_liability: is_isolated
+            || (has_perp_liability && perp_market.contract_tier == ContractTier::Isolated),
+        ..Default::default()
+    }))
 }
 
 // Utility functions
@@ -941,7 +1383,7 @@ pub fn can_be_liquidated(calculation: &SimplifiedMarginCalculation) -> bool {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use drift_program::{
         math::constants::{
             AMM_RESERVE_PRECISION, BASE_PRECISION_I64, MAX_CONCENTRATION_COEFFICIENT,
@@ -1296,6 +1738,42 @@ mod tests {
         assert_eq!(simplified.total_collateral, cached.total_collateral);
     }
 
+    #[test]
+    fn test_incremental_margin_calculation_missing_market() {
+        let (mut user, market_state) = create_simplified_test_setup();
+        let mut cached = IncrementalMarginCalculation::from_user(
+            &user,
+            &market_state,
+            MarginRequirementType::Initial,
+            1000,
+            0,
+        );
+        let free_collateral = cached.free_collateral();
+
+        user.perp_positions[0] = PerpPosition {
+            market_index: 5,
+            base_asset_amount: BASE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+        assert!(matches!(
+            IncrementalMarginCalculation::try_from_user(
+                &user,
+                &market_state,
+                MarginRequirementType::Initial,
+                1000,
+                0,
+            ),
+            Err(ErrorCode::PerpMarketNotFound)
+        ));
+        assert!(matches!(
+            cached.try_update_perp_position(&user.perp_positions[0], &market_state, 2000),
+            Err(ErrorCode::PerpMarketNotFound)
+        ));
+        // totals are untouched by the failed update
+        assert_eq!(cached.free_collateral(), free_collateral);
+        assert_eq!(cached.last_updated, 1000);
+    }
+
     pub fn amm_default_test() -> AMM {
         let default_reserves = 100 * AMM_RESERVE_PRECISION;
         // make sure tests don't have the default sqrt_k = 0
@@ -1332,7 +1810,7 @@ mod tests {
     }
 
     // Helper function to create a simple test setup for simplified margin calculation only
-    fn create_simplified_test_setup() -> (User, MarketState) {
+    pub(crate) fn create_simplified_test_setup() -> (User, MarketState) {
         // Create perp market
         let mut perp_market = PerpMarket {
             market_index: 0,
@@ -1503,6 +1981,52 @@ mod tests {
         assert!(calculation.margin_requirement > 0);
     }
 
+    #[test]
+    fn test_simplified_margin_calculation_cross_perp_without_quote_spot_market() {
+        let (mut user, full_market_state) = create_simplified_test_setup();
+        user.spot_positions = [SpotPosition::default(); 8];
+        user.perp_positions[0] = PerpPosition {
+            market_index: 0,
+            base_asset_amount: BASE_PRECISION_I64,
+            quote_asset_amount: -90 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+
+        // only the quote oracle price, no quote spot market
+        let mut market_state = MarketState::default();
+        market_state.set_perp_market(*full_market_state.get_perp_market(0));
+        market_state.set_perp_oracle_price(0, *full_market_state.get_perp_oracle_price(0).unwrap());
+        market_state.set_spot_oracle_price(0, *full_market_state.get_spot_oracle_price(0).unwrap());
+
+        let calculation = calculate_simplified_margin_requirement(
+            &user,
+            &market_state,
+            MarginRequirementType::Initial,
+            0,
+        )
+        .unwrap();
+        let expected = calculate_simplified_margin_requirement(
+            &user,
+            &full_market_state,
+            MarginRequirementType::Initial,
+            0,
+        )
+        .unwrap();
+        assert_eq!(calculation.total_collateral, expected.total_collateral);
+        assert_eq!(calculation.margin_requirement, expected.margin_requirement);
+
+        // strict mode needs the quote market twap
+        assert!(matches!(
+            calculate_strict_simplified_margin_requirement(
+                &user,
+                &market_state,
+                MarginRequirementType::Initial,
+                0,
+            ),
+            Err(ErrorCode::SpotMarketNotFound)
+        ));
+    }
+
     #[test]
     fn test_simplified_margin_calculation_with_perp_negative_pnl() {
         let (mut user, market_state) = create_simplified_test_setup();
@@ -2207,6 +2731,69 @@ mod tests {
         assert_eq!(simplified.free_collateral(), cached.free_collateral());
     }
 
+    #[test]
+    fn test_simplified_vs_cached_perp_custom_margin_ratio() {
+        let (mut user, market_state) = create_simplified_test_setup();
+        user.perp_positions[0] = PerpPosition {
+            market_index: 0,
+            base_asset_amount: BASE_PRECISION_I64,
+            quote_asset_amount: -200 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+
+        let initial = |user: &User| {
+            let simplified = calculate_simplified_margin_requirement(
+                user,
+                &market_state,
+                MarginRequirementType::Initial,
+                0,
+            )
+            .unwrap();
+            let cached = IncrementalMarginCalculation::from_user(
+                user,
+                &market_state,
+                MarginRequirementType::Initial,
+                1000,
+                0,
+            );
+            assert_eq!(simplified.total_collateral, cached.total_collateral);
+            assert_eq!(simplified.margin_requirement, cached.margin_requirement);
+            cached.margin_requirement
+        };
+        let base_requirement = initial(&user);
+
+        // per-position leverage cap (50%) above the market initial margin ratio (20%)
+        user.perp_positions[0].max_margin_ratio = 5000;
+        let position_requirement = initial(&user);
+        assert!(position_requirement > base_requirement);
+
+        // larger of user and position ratios applies
+        user.max_margin_ratio = 8000;
+        assert!(initial(&user) > position_requirement);
+
+        // maintenance margin ignores custom ratios
+        let maintenance = IncrementalMarginCalculation::from_user(
+            &user,
+            &market_state,
+            MarginRequirementType::Maintenance,
+            1000,
+            0,
+        );
+        user.perp_positions[0].max_margin_ratio = 0;
+        user.max_margin_ratio = 0;
+        let maintenance_default = IncrementalMarginCalculation::from_user(
+            &user,
+            &market_state,
+            MarginRequirementType::Maintenance,
+            1000,
+            0,
+        );
+        assert_eq!(
+            maintenance.margin_requirement,
+            maintenance_default.margin_requirement
+        );
+    }
+
     #[test]
     fn test_margin_buffer_functionality() {
         // Test margin buffer functionality
@@ -2296,4 +2883,340 @@ mod tests {
         assert!(iso_calc.margin_requirement > 0);
         assert!(iso_calc.total_collateral > 0);
     }
+
+    #[test]
+    fn test_simplified_vs_cached_isolated_perp_position() {
+        let (mut user, market_state) = create_simplified_test_setup();
+        user.perp_positions[0] = PerpPosition {
+            market_index: 0,
+            base_asset_amount: BASE_PRECISION_I64,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I64,
+            isolated_position_scaled_balance: 50 * SPOT_BALANCE_PRECISION_U64,
+            position_flag: 0b00000001,
+            ..PerpPosition::default()
+        };
+
+        for margin_buffer in [0, 10_000] {
+            let simplified = calculate_simplified_margin_requirement(
+                &user,
+                &market_state,
+                MarginRequirementType::Initial,
+                margin_buffer,
+            )
+            .unwrap();
+            let mut cached = IncrementalMarginCalculation::from_user(
+                &user,
+                &market_state,
+                MarginRequirementType::Initial,
+                1000,
+                margin_buffer,
+            );
+            let cached_simplified = cached.to_simplified();
+
+            // isolated position is excluded from the cross totals
+            assert_eq!(simplified.total_collateral, cached.total_collateral);
+            assert_eq!(simplified.margin_requirement, cached.margin_requirement);
+            assert_eq!(
+                simplified.margin_requirement_plus_buffer,
+                cached.margin_requirement_plus_buffer
+            );
+            assert!(cached_simplified.with_perp_isolated_liability);
+            assert!(!cached_simplified.with_spot_isolated_liability);
+
+            let expected = simplified.get_isolated_margin_calculation(0).unwrap();
+            let actual = cached_simplified
+                .get_isolated_margin_calculation(0)
+                .unwrap();
+            assert_eq!(expected.margin_requirement, actual.margin_requirement);
+            assert_eq!(expected.total_collateral, actual.total_collateral);
+            assert_eq!(
+                expected.total_collateral_buffer,
+                actual.total_collateral_buffer
+            );
+            assert_eq!(
+                expected.margin_requirement_plus_buffer,
+                actual.margin_requirement_plus_buffer
+            );
+
+            // moving the position back to cross margin folds it into the totals
+            let mut cross_position = user.perp_positions[0];
+            cross_position.position_flag = 0;
+            cross_position.isolated_position_scaled_balance = 0;
+            cached.update_perp_position(&cross_position, &market_state, 1001);
+            assert!(cached.margin_requirement > simplified.margin_requirement);
+            assert!(!cached.to_simplified().has_isolated_margin_calculation(0));
+        }
+    }
+
+    #[test]
+    fn test_simplified_vs_cached_pyth_override() {
+        let (mut user, mut market_state) = create_simplified_test_setup();
+        user.spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        user.perp_positions[0] = PerpPosition {
+            market_index: 0,
+            base_asset_amount: -BASE_PRECISION_I64,
+            quote_asset_amount: 200 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+        market_state.set_spot_pyth_price(1, 210 * PRICE_PRECISION_I64);
+        market_state.set_perp_pyth_price(0, 210 * PRICE_PRECISION_I64);
+
+        // 5% diff, pyth used below the threshold and oracle above it
+        for threshold_bps in [0, 1_000] {
+            market_state.pyth_oracle_diff_threshold_bps = threshold_bps;
+            let simplified = calculate_simplified_margin_requirement(
+                &user,
+                &market_state,
+                MarginRequirementType::Initial,
+                0,
+            )
+            .unwrap();
+            let cached = IncrementalMarginCalculation::from_user(
+                &user,
+                &market_state,
+                MarginRequirementType::Initial,
+                1000,
+                0,
+            );
+
+            assert_eq!(simplified.total_collateral, cached.total_collateral);
+            assert_eq!(simplified.margin_requirement, cached.margin_requirement);
+        }
+
+        let oracle = market_state.get_perp_oracle_price(0).unwrap().price;
+        market_state.pyth_oracle_diff_threshold_bps = 0;
+        assert_eq!(
+            market_state.get_perp_margin_price(0).unwrap().price,
+            210 * PRICE_PRECISION_I64
+        );
+        market_state.pyth_oracle_diff_threshold_bps = 1_000;
+        assert_eq!(market_state.get_perp_margin_price(0).unwrap().price, oracle);
+    }
+
+    #[test]
+    fn test_simplified_margin_calculation_strict() {
+        let (mut user, mut market_state) = create_simplified_test_setup();
+        let mut sol_market = *market_state.get_spot_market(1);
+        let calculate = |user: &User, market_state: &MarketState, strict| {
+            let calculate = if strict {
+                calculate_strict_simplified_margin_requirement
+            } else {
+                calculate_simplified_margin_requirement
+            };
+            calculate(user, market_state, MarginRequirementType::Initial, 0).unwrap()
+        };
+
+        // deposit valued at the lower of oracle ($200) and twap
+        user.spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64, // 1 SOL
+            ..SpotPosition::default()
+        };
+        sol_market
+            .historical_oracle_data
+            .last_oracle_price_twap_5min = 180 * PRICE_PRECISION_I64;
+        market_state.set_spot_market(sol_market);
+        assert_eq!(
+            calculate(&user, &market_state, false).total_collateral,
+            i128::from(210 * QUOTE_PRECISION_I64)
+        );
+        assert_eq!(
+            calculate(&user, &market_state, true).total_collateral,
+            i128::from(190 * QUOTE_PRECISION_I64)
+        );
+
+        // borrow valued at the higher of oracle and twap
+        user.spot_positions[1].balance_type = SpotBalanceType::Borrow;
+        sol_market
+            .historical_oracle_data
+            .last_oracle_price_twap_5min = 220 * PRICE_PRECISION_I64;
+        market_state.set_spot_market(sol_market);
+        assert_eq!(
+            calculate(&user, &market_state, false).margin_requirement,
+            200 * QUOTE_PRECISION_I64 as u128
+        );
+        assert_eq!(
+            calculate(&user, &market_state, true).margin_requirement,
+            220 * QUOTE_PRECISION_I64 as u128
+        );
+    }
+
+    #[test]
+    fn test_simplified_margin_breakdown() {
+        let (mut user, mut market_state) = create_simplified_test_setup();
+        let mut sol_market = *market_state.get_spot_market(1);
+        sol_market
+            .historical_oracle_data
+            .last_oracle_price_twap_5min = 180 * PRICE_PRECISION_I64;
+        market_state.set_spot_market(sol_market);
+        user.spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64, // 1 SOL
+            ..SpotPosition::default()
+        };
+        user.perp_positions[0] = PerpPosition {
+            market_index: 0,
+            base_asset_amount: BASE_PRECISION_I64,
+            quote_asset_amount: -200 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+
+        let breakdown = calculate_simplified_margin_breakdown(
+            &user,
+            &market_state,
+            MarginRequirementType::Initial,
+            0,
+            true,
+        )
+        .unwrap();
+
+        let usdc = breakdown.spot_positions[0];
+        assert_eq!(usdc.oracle_price, PRICE_PRECISION_I64);
+        assert_eq!(usdc.token_amount, 10 * QUOTE_PRECISION_I64 as i128);
+        assert_eq!(usdc.collateral, 10 * QUOTE_PRECISION_I64 as i128);
+        assert_eq!(usdc.weight, SPOT_WEIGHT_PRECISION);
+
+        // strict deposit valued at the lower twap
+        let sol = breakdown.spot_positions[1];
+        assert_eq!(sol.oracle_price, 180 * PRICE_PRECISION_I64);
+        assert_eq!(sol.value, 180 * QUOTE_PRECISION_I64 as i128);
+        assert_eq!(sol.weighted_value, 180 * QUOTE_PRECISION_I64 as i128);
+        assert_eq!(sol.collateral, 180 * QUOTE_PRECISION_I64 as i128);
+        assert_eq!(sol.weight, SPOT_WEIGHT_PRECISION);
+
+        // 20% initial margin on $200 of base
+        let perp = breakdown.perp_positions[0];
+        assert_eq!(perp.oracle_price, 200 * PRICE_PRECISION_I64);
+        assert_eq!(perp.token_amount, BASE_PRECISION_I64 as i128);
+        assert_eq!(perp.value, 200 * QUOTE_PRECISION_I64 as i128);
+        assert_eq!(perp.weighted_value, 40 * QUOTE_PRECISION_I64 as i128);
+        assert_eq!(perp.weight, 2000);
+        assert_eq!(perp.unrealized_pnl, 0);
+        assert_eq!(perp.margin_requirement, 40 * QUOTE_PRECISION_I64 as u128);
+        assert!(!perp.is_isolated);
+
+        assert_eq!(
+            breakdown.spot_positions[2],
+            PositionMarginBreakdown::default()
+        );
+        assert_eq!(
+            breakdown.perp_positions[1],
+            PositionMarginBreakdown::default()
+        );
+
+        let calculation = calculate_strict_simplified_margin_requirement(
+            &user,
+            &market_state,
+            MarginRequirementType::Initial,
+            0,
+        )
+        .unwrap();
+        assert_eq!(
+            calculation.total_collateral,
+            190 * QUOTE_PRECISION_I64 as i128
+        );
+        assert_eq!(
+            calculation.margin_requirement,
+            40 * QUOTE_PRECISION_I64 as u128
+        );
+
+        // same breakdown from the incremental calculation without strict prices
+        let incremental = IncrementalMarginCalculation::from_user(
+            &user,
+            &market_state,
+            MarginRequirementType::Initial,
+            1,
+            0,
+        );
+        assert_eq!(
+            incremental.breakdown(&user, &market_state).unwrap(),
+            calculate_simplified_margin_breakdown(
+                &user,
+                &market_state,
+                MarginRequirementType::Initial,
+                0,
+                false,
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_simplified_margin_calculation_batch() {
+        use abi_stable::std_types::{RResult, RSlice, RSliceMut};
+
+        let (user, market_state) = create_simplified_test_setup();
+        let mut users = vec![user; 9];
+        for (i, user) in users.iter_mut().enumerate() {
+            user.spot_positions[0].scaled_balance *= i as u64 + 1;
+        }
+        // unknown spot market
+        users[4].spot_positions[1] = SpotPosition {
+            market_index: 99,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        for threads in [0, 1, 4, 16] {
+            let mut out: Vec<_> = users
+                .iter()
+                .map(|_| std::mem::MaybeUninit::uninit())
+                .collect();
+            let result = crate::exports::margin_calculate_simplified_margin_requirement_batch(
+                RSlice::from_slice(&users),
+                &market_state,
+                MarginRequirementType::Initial,
+                0,
+                false,
+                threads,
+                RSliceMut::from_mut_slice(&mut out),
+            );
+            assert!(result.is_ok());
+
+            for (user, out) in users.iter().zip(out) {
+                let expected = calculate_simplified_margin_requirement(
+                    user,
+                    &market_state,
+                    MarginRequirementType::Initial,
+                    0,
+                );
+                match (expected, unsafe { out.assume_init() }) {
+                    (Ok(expected), RResult::ROk(actual)) => {
+                        assert_eq!(expected.total_collateral, actual.total_collateral.0);
+                        assert_eq!(expected.margin_requirement, actual.margin_requirement.0);
+                    }
+                    (Err(err), RResult::RErr(code)) => assert_eq!(u32::from(err), code),
+                    _ => panic!("batch result differs from single user calculation"),
+                }
+            }
+        }
+
+        let mut out: Vec<_> = users[1..]
+            .iter()
+            .map(|_| std::mem::MaybeUninit::uninit())
+            .collect();
+        let result = crate::exports::margin_calculate_simplified_margin_requirement_batch(
+            RSlice::from_slice(&users),
+            &market_state,
+            MarginRequirementType::Initial,
+            0,
+            false,
+            0,
+            RSliceMut::from_mut_slice(&mut out),
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            u32::from(crate::types::FfiErrorCode::BufferTooSmall)
+        );
+    }
 }
+
+#[cfg(test)]
+mod differential_tests;
@@ -0,0 +1,993 @@
+//! Account risk analytics built on the simplified margin calculation
+use drift_program::{
+    controller::{
+        position::PositionDirection,
+        spot_position::update_spot_balances_and_cumulative_deposits_with_limits,
+    },
+    error::{DriftResult, ErrorCode},
+    math::{
+        constants::{MARGIN_PRECISION_U128, PERCENTAGE_PRECISION_I128},
+        margin::MarginRequirementType,
+        spot_balance::get_token_amount,
+    },
+    state::{
+        spot_market::SpotBalanceType,
+        user::{MarketType, User},
+    },
+};
+
+use crate::{
+    margin::{
+        calculate_simplified_margin_breakdown, calculate_simplified_margin_requirement,
+        calculate_strict_simplified_margin_requirement, MarginBreakdown, PositionMarginBreakdown,
+    },
+    types::{AccountRiskMetrics, MarketState, MaxWithdrawAmount, PriceShock, RiskMetrics},
+};
+
+/// Upper bound of the liquidation price search as a multiple of the current price
+const MAX_LIQUIDATION_PRICE_MULTIPLE: i64 = 1_000;
+
+/// Upper bound of the max order size search in base asset amount
+const MAX_ORDER_SIZE: u64 = i64::MAX as u64 / 4;
+
+/// Oracle price of the `market_type` market at which `user`'s maintenance free collateral reaches
+/// zero, every other price held fixed
+///
+/// Isolated perp positions are measured against their own bucket, everything else against the
+/// cross account. Returns the current price if the account is already liquidatable and `None` if
+/// no price between 0 and `MAX_LIQUIDATION_PRICE_MULTIPLE` times the current price liquidates it.
+/// When both a lower and a higher liquidation price exist the one nearest the current price is
+/// returned.
+pub fn calculate_liquidation_price(
+    user: &User,
+    market_state: &MarketState,
+    market_type: MarketType,
+    market_index: u16,
+) -> DriftResult<Option<i64>> {
+    let oracle_price = market_state
+        .get_margin_price(market_type, market_index)
+        .ok_or(ErrorCode::OracleNotFound)?
+        .price;
+    let isolated = market_type == MarketType::Perp
+        && user
+            .perp_positions
+            .iter()
+            .any(|p| p.market_index == market_index && !p.is_available() && p.is_isolated());
+
+    let mut market_state = market_state.clone();
+    let mut free_collateral = |price: i64| -> DriftResult<i128> {
+        market_state.set_margin_price(market_type, market_index, price);
+        let calculation = calculate_simplified_margin_requirement(
+            user,
+            &market_state,
+            MarginRequirementType::Maintenance,
+            0,
+        )?;
+        Ok(if isolated {
+            calculation
+                .get_isolated_free_collateral(market_index)
+                .unwrap_or_default()
+        } else {
+            calculation.free_collateral()
+        })
+    };
+
+    if free_collateral(oracle_price)? <= 0 {
+        return Ok(Some(oracle_price));
+    }
+    let lower = find_liquidation_price(&mut free_collateral, oracle_price, 1)?;
+    let upper = find_liquidation_price(
+        &mut free_collateral,
+        oracle_price,
+        oracle_price.saturating_mul(MAX_LIQUIDATION_PRICE_MULTIPLE),
+    )?;
+
+    Ok(match (lower, upper) {
+        (Some(lower), Some(upper)) => {
+            if oracle_price - lower <= upper - oracle_price {
+                Some(lower)
+            } else {
+                Some(upper)
+            }
+        }
+        (lower, upper) => lower.or(upper),
+    })
+}
+
+/// Bisect between `safe` (positive free collateral) and `bound` for the price nearest `safe` with
+/// non-positive free collateral, `None` if `bound` is still safe
+fn find_liquidation_price(
+    free_collateral: &mut impl FnMut(i64) -> DriftResult<i128>,
+    mut safe: i64,
+    bound: i64,
+) -> DriftResult<Option<i64>> {
+    if free_collateral(bound)? > 0 {
+        return Ok(None);
+    }
+    let mut liquidated = bound;
+    while safe.abs_diff(liquidated) > 1 {
+        let mid = safe + (liquidated - safe) / 2;
+        if free_collateral(mid)? > 0 {
+            safe = mid;
+        } else {
+            liquidated = mid;
+        }
+    }
+    Ok(Some(liquidated))
+}
+
+/// Largest base asset amount `user` can order in the `market_index` perp market in `direction`
+/// while still meeting the margin requirement, a multiple of the market's order step size
+///
+/// The order is simulated as an open order on the position so existing positions being reduced or
+/// flipped, high leverage mode and user/position max margin ratios are applied the same way as the
+/// margin calculation. Like the program, the portion of the order reducing the current position
+/// only needs to meet maintenance margin while any increase must meet (strict) initial margin.
+/// Returns 0 if no order can be placed.
+pub fn calculate_max_perp_order_size(
+    user: &User,
+    market_state: &MarketState,
+    market_index: u16,
+    direction: PositionDirection,
+) -> DriftResult<u64> {
+    let step_size = market_state
+        .try_get_perp_market(market_index)?
+        .amm
+        .order_step_size
+        .max(1);
+
+    let position_index = user
+        .perp_positions
+        .iter()
+        .position(|p| p.market_index == market_index && !p.is_available())
+        .or_else(|| user.perp_positions.iter().position(|p| p.is_available()))
+        .ok_or(ErrorCode::MaxNumberOfPositions)?;
+    let position = user.perp_positions[position_index];
+    let reducing_size = match direction {
+        PositionDirection::Long if position.base_asset_amount < 0 => {
+            position.base_asset_amount.unsigned_abs()
+        }
+        PositionDirection::Short if position.base_asset_amount > 0 => {
+            position.base_asset_amount.unsigned_abs()
+        }
+        _ => 0,
+    };
+
+    let mut user = *user;
+    let mut allowed = |steps: u64| -> DriftResult<bool> {
+        let size = steps * step_size;
+        let order_position = &mut user.perp_positions[position_index];
+        *order_position = position;
+        order_position.market_index = market_index;
+        order_position.open_orders += 1;
+        match direction {
+            PositionDirection::Long => order_position.open_bids += size as i64,
+            PositionDirection::Short => order_position.open_asks -= size as i64,
+        }
+
+        let margin_type = if size > reducing_size {
+            MarginRequirementType::Initial
+        } else {
+            MarginRequirementType::Maintenance
+        };
+        calculate_strict_simplified_margin_requirement(&user, market_state, margin_type, 0)
+            .map(|calculation| calculation.meets_margin_requirement())
+    };
+
+    if !allowed(0)? {
+        return Ok(0);
+    }
+
+    // margin requirement only grows past the reducing size, find the first failing size by
+    // doubling then bisect back to the last passing one
+    let max_steps = MAX_ORDER_SIZE / step_size;
+    let mut passing = 0;
+    let mut failing = 1;
+    while allowed(failing)? {
+        passing = failing;
+        if failing == max_steps {
+            return Ok(max_steps * step_size);
+        }
+        failing = (failing * 2).min(max_steps);
+    }
+    while failing - passing > 1 {
+        let mid = passing + (failing - passing) / 2;
+        if allowed(mid)? {
+            passing = mid;
+        } else {
+            failing = mid;
+        }
+    }
+
+    Ok(passing * step_size)
+}
+
+/// Largest token amounts `user` can withdraw from the `market_index` spot market, with and without
+/// borrowing
+///
+/// Each amount is applied to copies of the user and spot market the way the program's withdraw
+/// does, so the withdraw guard (`check_withdraw_limits`), insufficient deposits and utilization
+/// caps apply, then the user must still meet strict initial margin on the simplified margin
+/// calculation. Any program error while applying the withdraw is treated as the amount being too
+/// large. Interest is not accrued, `market_state` should be recent.
+pub fn calculate_max_withdraw_amount(
+    user: &User,
+    market_state: &MarketState,
+    market_index: u16,
+) -> DriftResult<MaxWithdrawAmount> {
+    let spot_market = *market_state.try_get_spot_market(market_index)?;
+    let deposit_amount = match user.get_spot_position(market_index) {
+        Ok(position) if position.balance_type == SpotBalanceType::Deposit => {
+            position.get_token_amount(&spot_market)?
+        }
+        _ => 0,
+    };
+    let pool_deposit_amount = get_token_amount(
+        spot_market.deposit_balance,
+        &spot_market,
+        &SpotBalanceType::Deposit,
+    )?;
+    let deposit_amount = u64::try_from(deposit_amount).unwrap_or(u64::MAX);
+    let pool_deposit_amount = u64::try_from(pool_deposit_amount).unwrap_or(u64::MAX);
+
+    let mut market_state = market_state.clone();
+    let mut allowed = |amount: u64| -> DriftResult<bool> {
+        let mut user = *user;
+        let mut withdraw_market = spot_market;
+        let withdrawn = user
+            .force_get_spot_position_index(market_index)
+            .and_then(|_| {
+                update_spot_balances_and_cumulative_deposits_with_limits(
+                    amount as u128,
+                    &SpotBalanceType::Borrow,
+                    &mut withdraw_market,
+                    &mut user,
+                )
+            });
+        if withdrawn.is_err() {
+            return Ok(false);
+        }
+
+        market_state.set_spot_market(withdraw_market);
+        calculate_strict_simplified_margin_requirement(
+            &user,
+            &market_state,
+            MarginRequirementType::Initial,
+            0,
+        )
+        .map(|calculation| calculation.meets_margin_requirement())
+    };
+
+    if !allowed(0)? {
+        return Ok(MaxWithdrawAmount::default());
+    }
+    let withdraw = find_max_allowed(&mut allowed, 0, deposit_amount)?;
+    let withdraw_with_borrow = if withdraw == deposit_amount {
+        find_max_allowed(&mut allowed, withdraw, pool_deposit_amount.max(withdraw))?
+    } else {
+        withdraw
+    };
+
+    Ok(MaxWithdrawAmount {
+        withdraw,
+        withdraw_with_borrow,
+    })
+}
+
+/// Bisect for the largest amount up to `bound` passing `allowed`, `passing` must pass
+fn find_max_allowed(
+    allowed: &mut impl FnMut(u64) -> DriftResult<bool>,
+    mut passing: u64,
+    bound: u64,
+) -> DriftResult<u64> {
+    if allowed(bound)? {
+        return Ok(bound);
+    }
+    let mut failing = bound;
+    while failing - passing > 1 {
+        let mid = passing + (failing - passing) / 2;
+        if allowed(mid)? {
+            passing = mid;
+        } else {
+            failing = mid;
+        }
+    }
+    Ok(passing)
+}
+
+/// Maintenance risk metrics of `user` on the simplified margin calculation, see
+/// `calculate_risk_metrics_from_breakdown`
+pub fn calculate_risk_metrics(
+    user: &User,
+    market_state: &MarketState,
+) -> DriftResult<AccountRiskMetrics> {
+    calculate_simplified_margin_breakdown(
+        user,
+        market_state,
+        MarginRequirementType::Maintenance,
+        0,
+        false,
+    )
+    .and_then(|breakdown| calculate_risk_metrics_from_breakdown(&breakdown))
+}
+
+/// Risk metrics of the cross account and each isolated perp position from a maintenance margin
+/// breakdown of either margin engine
+///
+/// Same formulas as the Drift SDK/UI: health is `1 - margin requirement / total collateral` as a
+/// rounded percentage, leverage is total notional over net asset value (spot assets less spot
+/// liabilities plus perp pnl, the isolated deposit plus pnl for isolated positions) and the margin
+/// ratio is total collateral over total notional. Perp pnl is the weighted pnl of the breakdown.
+///
+/// Fails with `MathError` if the breakdown values overflow the metrics
+pub fn calculate_risk_metrics_from_breakdown(
+    breakdown: &MarginBreakdown,
+) -> DriftResult<AccountRiskMetrics> {
+    let mut metrics = AccountRiskMetrics::default();
+    let mut cross = RiskTotals::default();
+
+    for position in &breakdown.spot_positions {
+        cross.add_spot(position)?;
+    }
+
+    for (position, isolated) in breakdown
+        .perp_positions
+        .iter()
+        .zip(metrics.isolated.iter_mut())
+    {
+        if position.is_isolated {
+            let mut totals = RiskTotals::default();
+            totals.add_perp(position)?;
+            // isolated collateral is the position's own deposit plus its pnl
+            totals.net_asset_value = position.collateral;
+            *isolated = totals.metrics()?;
+        } else {
+            cross.add_perp(position)?;
+        }
+    }
+
+    metrics.cross = cross.metrics()?;
+    Ok(metrics)
+}
+
+/// Margin totals of the cross account or an isolated position
+#[derive(Default)]
+struct RiskTotals {
+    total_collateral: i128,
+    margin_requirement: u128,
+    total_notional: u128,
+    net_asset_value: i128,
+}
+
+impl RiskTotals {
+    fn add_spot(&mut self, position: &PositionMarginBreakdown) -> DriftResult {
+        self.add_margin(position)?;
+        // only spot liabilities count towards notional
+        if position.value < 0 {
+            self.total_notional = self
+                .total_notional
+                .checked_add(position.value.unsigned_abs())
+                .ok_or(ErrorCode::MathError)?;
+        }
+        self.net_asset_value = self
+            .net_asset_value
+            .checked_add(position.value)
+            .ok_or(ErrorCode::MathError)?;
+        Ok(())
+    }
+
+    fn add_perp(&mut self, position: &PositionMarginBreakdown) -> DriftResult {
+        self.add_margin(position)?;
+        self.total_notional = self
+            .total_notional
+            .checked_add(position.value.unsigned_abs())
+            .ok_or(ErrorCode::MathError)?;
+        self.net_asset_value = self
+            .net_asset_value
+            .checked_add(position.unrealized_pnl)
+            .ok_or(ErrorCode::MathError)?;
+        Ok(())
+    }
+
+    fn add_margin(&mut self, position: &PositionMarginBreakdown) -> DriftResult {
+        self.total_collateral = self
+            .total_collateral
+            .checked_add(position.collateral)
+            .ok_or(ErrorCode::MathError)?;
+        self.margin_requirement = self
+            .margin_requirement
+            .checked_add(position.margin_requirement)
+            .ok_or(ErrorCode::MathError)?;
+        Ok(())
+    }
+
+    fn metrics(&self) -> DriftResult<RiskMetrics> {
+        let leverage = if self.total_notional == 0 {
+            0
+        } else if self.net_asset_value <= 0 {
+            u128::MAX
+        } else {
+            self.total_notional
+                .checked_mul(MARGIN_PRECISION_U128)
+                .ok_or(ErrorCode::MathError)?
+                / self.net_asset_value.unsigned_abs()
+        };
+        let margin_ratio = if self.total_notional == 0 {
+            u128::MAX
+        } else {
+            self.total_collateral
+                .max(0)
+                .unsigned_abs()
+                .checked_mul(MARGIN_PRECISION_U128)
+                .ok_or(ErrorCode::MathError)?
+                / self.total_notional
+        };
+        let distance_to_liquidation = i128::try_from(self.margin_requirement)
+            .ok()
+            .and_then(|margin_requirement| self.total_collateral.checked_sub(margin_requirement))
+            .ok_or(ErrorCode::MathError)?;
+
+        Ok(RiskMetrics {
+            total_collateral: self.total_collateral.into(),
+            margin_requirement: self.margin_requirement.into(),
+            distance_to_liquidation: distance_to_liquidation.into(),
+            total_notional: self.total_notional.into(),
+            leverage: leverage.into(),
+            margin_ratio: margin_ratio.into(),
+            health: health(self.total_collateral, self.margin_requirement)?,
+        })
+    }
+}
+
+/// Health percentage as shown by the Drift UI, rounded half up
+fn health(total_collateral: i128, margin_requirement: u128) -> DriftResult<u8> {
+    if margin_requirement == 0 && total_collateral >= 0 {
+        return Ok(100);
+    }
+    if total_collateral <= 0 {
+        return Ok(0);
+    }
+    let total_collateral = total_collateral.unsigned_abs();
+    let free_collateral = total_collateral.saturating_sub(margin_requirement);
+    free_collateral
+        .checked_mul(200)
+        .and_then(|free_collateral| free_collateral.checked_add(total_collateral))
+        .map(|health| health / (2 * total_collateral))
+        .map(|health| health as u8)
+        .ok_or(ErrorCode::MathError)
+}
+
+/// Copy of `market_state` with each market's margin price moved by its relative `shocks`
+///
+/// Several shocks of the same market compound. Fails with `OracleNotFound` for a market without a
+/// price and `InvalidOracle` if a shock takes a price below zero
+pub fn apply_price_shocks(
+    market_state: &MarketState,
+    shocks: &[PriceShock],
+) -> DriftResult<MarketState> {
+    let mut shocked = market_state.clone();
+    for shock in shocks {
+        let price = shocked
+            .get_margin_price(shock.market_type, shock.market_index)
+            .ok_or(ErrorCode::OracleNotFound)?
+            .price;
+        let shocked_price = (price as i128)
+            .checked_mul(PERCENTAGE_PRECISION_I128 + shock.shock as i128)
+            .map(|price| price / PERCENTAGE_PRECISION_I128)
+            .and_then(|price| i64::try_from(price).ok())
+            .ok_or(ErrorCode::MathError)?;
+        if shocked_price < 0 {
+            return Err(ErrorCode::InvalidOracle);
+        }
+        shocked.set_margin_price(shock.market_type, shock.market_index, shocked_price);
+    }
+    Ok(shocked)
+}
+
+#[cfg(test)]
+mod tests {
+    use drift_program::{
+        math::constants::{
+            BASE_PRECISION_I64, BASE_PRECISION_U64, PRICE_PRECISION_I64, QUOTE_PRECISION_I128,
+            QUOTE_PRECISION_I64, QUOTE_PRECISION_U128, QUOTE_PRECISION_U64,
+            SPOT_BALANCE_PRECISION_U64,
+        },
+        state::{
+            spot_market::SpotBalanceType,
+            user::{PerpPosition, SpotPosition},
+        },
+    };
+
+    use super::*;
+    use crate::margin::tests::create_simplified_test_setup;
+
+    // $100 USDC collateral, SOL spot/perp at $200 with 10% perp maintenance margin
+    fn setup() -> (User, MarketState) {
+        let (mut user, market_state) = create_simplified_test_setup();
+        user.spot_positions[0].scaled_balance = 100 * SPOT_BALANCE_PRECISION_U64;
+        (user, market_state)
+    }
+
+    fn sol_perp_long() -> PerpPosition {
+        PerpPosition {
+            market_index: 0,
+            base_asset_amount: BASE_PRECISION_I64,
+            quote_asset_amount: -200 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }
+    }
+
+    #[test]
+    fn liquidation_price_perp_long() {
+        let (mut user, market_state) = setup();
+        user.perp_positions[0] = sol_perp_long();
+
+        // 100 + (p - 200) - 0.1p = 0
+        let price = calculate_liquidation_price(&user, &market_state, MarketType::Perp, 0)
+            .unwrap()
+            .unwrap();
+        assert!(price.abs_diff(111_111_111) <= 1, "{price}");
+    }
+
+    #[test]
+    fn liquidation_price_spot_borrow() {
+        let (mut user, market_state) = setup();
+        user.spot_positions[0].scaled_balance = 300 * SPOT_BALANCE_PRECISION_U64;
+        user.spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Borrow,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64, // 1 SOL
+            ..SpotPosition::default()
+        };
+
+        // 300 - p = 0, liquidated as the price rises
+        let price = calculate_liquidation_price(&user, &market_state, MarketType::Spot, 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(price, 300 * PRICE_PRECISION_I64);
+    }
+
+    #[test]
+    fn liquidation_price_isolated_perp() {
+        let (mut user, market_state) = setup();
+        user.perp_positions[0] = PerpPosition {
+            isolated_position_scaled_balance: 50 * SPOT_BALANCE_PRECISION_U64,
+            position_flag: 0b00000001, // isolated
+            ..sol_perp_long()
+        };
+
+        // cross collateral is not available to the isolated position: 50 + (p - 200) - 0.1p = 0
+        let price = calculate_liquidation_price(&user, &market_state, MarketType::Perp, 0)
+            .unwrap()
+            .unwrap();
+        assert!(price.abs_diff(166_666_667) <= 1, "{price}");
+    }
+
+    #[test]
+    fn liquidation_price_none_without_exposure() {
+        let (user, market_state) = setup();
+        assert_eq!(
+            calculate_liquidation_price(&user, &market_state, MarketType::Perp, 0).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn liquidation_price_already_liquidatable() {
+        let (mut user, market_state) = setup();
+        user.spot_positions[0].scaled_balance = SPOT_BALANCE_PRECISION_U64;
+        user.perp_positions[0] = sol_perp_long();
+
+        assert_eq!(
+            calculate_liquidation_price(&user, &market_state, MarketType::Perp, 0).unwrap(),
+            Some(200 * PRICE_PRECISION_I64)
+        );
+    }
+
+    #[test]
+    fn max_perp_order_size_flat() {
+        let (user, market_state) = setup();
+
+        // 100 / (200 * 0.2) = 2.5 SOL, less the open order margin charge
+        let size = calculate_max_perp_order_size(&user, &market_state, 0, PositionDirection::Long)
+            .unwrap();
+        assert!(
+            (249 * BASE_PRECISION_U64 / 100..=5 * BASE_PRECISION_U64 / 2).contains(&size),
+            "{size}"
+        );
+        assert_eq!(size % 1_000, 0);
+        assert_eq!(
+            calculate_max_perp_order_size(&user, &market_state, 0, PositionDirection::Short)
+                .unwrap(),
+            size
+        );
+    }
+
+    #[test]
+    fn max_perp_order_size_flips_position() {
+        let (mut user, market_state) = setup();
+        user.perp_positions[0] = PerpPosition {
+            market_index: 0,
+            base_asset_amount: -BASE_PRECISION_I64,
+            quote_asset_amount: 200 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+
+        // closes the 1 SOL short then opens up to 2.5 SOL long
+        let size = calculate_max_perp_order_size(&user, &market_state, 0, PositionDirection::Long)
+            .unwrap();
+        assert!(
+            (349 * BASE_PRECISION_U64 / 100..=7 * BASE_PRECISION_U64 / 2).contains(&size),
+            "{size}"
+        );
+
+        // adding to the short is limited by the margin already used
+        let size = calculate_max_perp_order_size(&user, &market_state, 0, PositionDirection::Short)
+            .unwrap();
+        assert!(
+            (149 * BASE_PRECISION_U64 / 100..=3 * BASE_PRECISION_U64 / 2).contains(&size),
+            "{size}"
+        );
+    }
+
+    #[test]
+    fn max_perp_order_size_max_margin_ratio() {
+        let (mut user, market_state) = setup();
+        user.max_margin_ratio = 5000; // 50%
+
+        let size = calculate_max_perp_order_size(&user, &market_state, 0, PositionDirection::Long)
+            .unwrap();
+        assert!(
+            (99 * BASE_PRECISION_U64 / 100..=BASE_PRECISION_U64).contains(&size),
+            "{size}"
+        );
+    }
+
+    #[test]
+    fn max_perp_order_size_below_maintenance() {
+        let (mut user, market_state) = setup();
+        user.spot_positions[0].scaled_balance = SPOT_BALANCE_PRECISION_U64;
+        user.perp_positions[0] = sol_perp_long();
+
+        assert_eq!(
+            calculate_max_perp_order_size(&user, &market_state, 0, PositionDirection::Short)
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn max_withdraw_amount_deposit_only() {
+        let (user, market_state) = setup();
+
+        // borrowing against nothing fails, withdrawing the full deposit is the limit
+        assert_eq!(
+            calculate_max_withdraw_amount(&user, &market_state, 0).unwrap(),
+            MaxWithdrawAmount {
+                withdraw: 100 * QUOTE_PRECISION_U64,
+                withdraw_with_borrow: 100 * QUOTE_PRECISION_U64,
+            }
+        );
+    }
+
+    #[test]
+    fn max_withdraw_amount_limited_by_margin() {
+        let (mut user, market_state) = setup();
+        user.perp_positions[0] = sol_perp_long();
+
+        // 1 SOL perp at $200 with 20% initial margin keeps $40
+        assert_eq!(
+            calculate_max_withdraw_amount(&user, &market_state, 0).unwrap(),
+            MaxWithdrawAmount {
+                withdraw: 60 * QUOTE_PRECISION_U64,
+                withdraw_with_borrow: 60 * QUOTE_PRECISION_U64,
+            }
+        );
+    }
+
+    #[test]
+    fn max_withdraw_amount_borrow() {
+        let (user, mut market_state) = setup();
+        let mut sol_market = *market_state.get_spot_market(1);
+        let one_sol = 10_u64.pow(sol_market.decimals);
+        sol_market.withdraw_guard_threshold = 1_000 * one_sol;
+        market_state.set_spot_market(sol_market);
+
+        // $100 USDC collateral covers a $100 SOL borrow at 100% liability weight
+        assert_eq!(
+            calculate_max_withdraw_amount(&user, &market_state, 1).unwrap(),
+            MaxWithdrawAmount {
+                withdraw: 0,
+                withdraw_with_borrow: one_sol / 2,
+            }
+        );
+    }
+
+    #[test]
+    fn risk_metrics_no_positions() {
+        let (user, market_state) = setup();
+        let metrics = calculate_risk_metrics(&user, &market_state).unwrap();
+
+        assert_eq!(metrics.cross.health, 100);
+        assert_eq!(metrics.cross.total_notional.0, 0);
+        assert_eq!(metrics.cross.leverage.0, 0);
+        assert_eq!(metrics.cross.margin_ratio.0, u128::MAX);
+        assert_eq!(
+            metrics.cross.distance_to_liquidation.0,
+            100 * QUOTE_PRECISION_I128
+        );
+    }
+
+    #[test]
+    fn risk_metrics_perp_long() {
+        let (mut user, market_state) = setup();
+        user.perp_positions[0] = sol_perp_long();
+        let metrics = calculate_risk_metrics(&user, &market_state).unwrap();
+
+        // $100 collateral, $200 notional with 10% maintenance margin
+        assert_eq!(
+            metrics.cross,
+            RiskMetrics {
+                total_collateral: (100 * QUOTE_PRECISION_I128).into(),
+                margin_requirement: (20 * QUOTE_PRECISION_U128).into(),
+                distance_to_liquidation: (80 * QUOTE_PRECISION_I128).into(),
+                total_notional: (200 * QUOTE_PRECISION_U128).into(),
+                leverage: (2 * MARGIN_PRECISION_U128).into(),
+                margin_ratio: (MARGIN_PRECISION_U128 / 2).into(),
+                health: 80,
+            }
+        );
+        assert_eq!(metrics.isolated, [RiskMetrics::default(); 8]);
+    }
+
+    #[test]
+    fn risk_metrics_isolated_perp() {
+        let (mut user, market_state) = setup();
+        user.perp_positions[0] = PerpPosition {
+            isolated_position_scaled_balance: 50 * SPOT_BALANCE_PRECISION_U64,
+            position_flag: 0b00000001, // isolated
+            ..sol_perp_long()
+        };
+        let metrics = calculate_risk_metrics(&user, &market_state).unwrap();
+
+        assert_eq!(metrics.cross.health, 100);
+        assert_eq!(metrics.cross.leverage.0, 0);
+        assert_eq!(metrics.cross.margin_ratio.0, u128::MAX);
+        assert_eq!(
+            metrics.isolated[0],
+            RiskMetrics {
+                total_collateral: (50 * QUOTE_PRECISION_I128).into(),
+                margin_requirement: (20 * QUOTE_PRECISION_U128).into(),
+                distance_to_liquidation: (30 * QUOTE_PRECISION_I128).into(),
+                total_notional: (200 * QUOTE_PRECISION_U128).into(),
+                leverage: (4 * MARGIN_PRECISION_U128).into(),
+                margin_ratio: (MARGIN_PRECISION_U128 / 4).into(),
+                health: 60,
+            }
+        );
+    }
+
+    #[test]
+    fn risk_metrics_spot_borrow() {
+        let (mut user, market_state) = setup();
+        user.spot_positions[0].scaled_balance = 300 * SPOT_BALANCE_PRECISION_U64;
+        user.spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Borrow,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64, // 1 SOL
+            ..SpotPosition::default()
+        };
+        let metrics = calculate_risk_metrics(&user, &market_state).unwrap();
+
+        // $300 deposit less a $200 borrow leaves $100 net asset value
+        assert_eq!(metrics.cross.total_notional.0, 200 * QUOTE_PRECISION_U128);
+        assert_eq!(metrics.cross.leverage.0, 2 * MARGIN_PRECISION_U128);
+        assert_eq!(metrics.cross.margin_ratio.0, 3 * MARGIN_PRECISION_U128 / 2);
+        assert_eq!(metrics.cross.health, 33);
+    }
+
+    #[test]
+    fn risk_metrics_health_rounding() {
+        let health = |total_collateral, margin_requirement| {
+            health(total_collateral, margin_requirement).unwrap()
+        };
+        assert_eq!(health(0, 0), 100);
+        assert_eq!(health(-1, 0), 0);
+        assert_eq!(health(100, 100), 0);
+        assert_eq!(health(100, 150), 0);
+        assert_eq!(health(200, 1), 100);
+        assert_eq!(health(200, 3), 99);
+        assert_eq!(health(1000, 5), 100);
+        assert_eq!(health(1000, 6), 99);
+    }
+
+    #[test]
+    fn risk_metrics_overflowing_breakdown() {
+        let position = |collateral, margin_requirement, value| PositionMarginBreakdown {
+            collateral,
+            margin_requirement,
+            value,
+            ..PositionMarginBreakdown::default()
+        };
+        let cases = [
+            // total collateral overflows
+            [position(i128::MAX, 0, 0), position(1, 0, 0)],
+            // margin requirement overflows
+            [position(0, u128::MAX, 0), position(0, 1, 0)],
+            // requirement beyond the collateral range
+            [position(0, u128::MAX, 0), position(0, 0, 0)],
+            // collateral overflows the margin ratio precision
+            [position(i128::MAX, 0, 0), position(0, 0, -1)],
+        ];
+
+        for [first, second] in cases {
+            let mut breakdown = MarginBreakdown::default();
+            breakdown.spot_positions[0] = first;
+            breakdown.spot_positions[1] = second;
+            assert!(matches!(
+                calculate_risk_metrics_from_breakdown(&breakdown),
+                Err(ErrorCode::MathError)
+            ));
+        }
+
+        // notional overflows the leverage precision of an isolated position
+        let mut breakdown = MarginBreakdown::default();
+        breakdown.perp_positions[0] = PositionMarginBreakdown {
+            is_isolated: true,
+            ..position(1, 0, i128::MAX)
+        };
+        assert!(matches!(
+            calculate_risk_metrics_from_breakdown(&breakdown),
+            Err(ErrorCode::MathError)
+        ));
+    }
+
+    #[test]
+    fn price_shocks() {
+        let (_, market_state) = setup();
+        let shock = |market_type, market_index, shock| PriceShock {
+            market_type,
+            market_index,
+            shock,
+        };
+
+        let shocked = apply_price_shocks(
+            &market_state,
+            &[
+                shock(MarketType::Perp, 0, -100_000),
+                shock(MarketType::Spot, 1, 500_000),
+                shock(MarketType::Spot, 1, -500_000),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            shocked.get_margin_price(MarketType::Perp, 0).unwrap().price,
+            180 * PRICE_PRECISION_I64
+        );
+        // +50% then -50% compounds to -25%
+        assert_eq!(
+            shocked.get_margin_price(MarketType::Spot, 1).unwrap().price,
+            150 * PRICE_PRECISION_I64
+        );
+        assert_eq!(
+            market_state
+                .get_margin_price(MarketType::Perp, 0)
+                .unwrap()
+                .price,
+            200 * PRICE_PRECISION_I64
+        );
+
+        assert_eq!(
+            apply_price_shocks(&market_state, &[shock(MarketType::Spot, 1, -1_000_000)])
+                .unwrap()
+                .get_margin_price(MarketType::Spot, 1)
+                .unwrap()
+                .price,
+            0
+        );
+        assert!(matches!(
+            apply_price_shocks(&market_state, &[shock(MarketType::Spot, 1, -1_500_000)]),
+            Err(ErrorCode::InvalidOracle)
+        ));
+        assert!(matches!(
+            apply_price_shocks(&market_state, &[shock(MarketType::Perp, 42, 100_000)]),
+            Err(ErrorCode::OracleNotFound)
+        ));
+    }
+
+    #[test]
+    fn stress_grid() {
+        use abi_stable::std_types::{RResult, RSlice, RSliceMut};
+
+        let (user, market_state) = setup();
+        let mut long = user;
+        long.perp_positions[0] = sol_perp_long();
+        let users = [user, long];
+
+        let sol_shock = |shock| PriceShock {
+            market_type: MarketType::Perp,
+            market_index: 0,
+            shock,
+        };
+        let down = [sol_shock(-300_000)];
+        let up = [sol_shock(300_000)];
+        let missing = [PriceShock {
+            market_type: MarketType::Spot,
+            market_index: 42,
+            shock: 0,
+        }];
+        let scenarios = [
+            RSlice::from_slice(&[]),
+            RSlice::from_slice(&down),
+            RSlice::from_slice(&up),
+            RSlice::from_slice(&missing),
+        ];
+
+        for threads in [0, 1, 4] {
+            let mut out: Vec<_> = (0..users.len() * scenarios.len())
+                .map(|_| std::mem::MaybeUninit::uninit())
+                .collect();
+            let result = crate::exports::risk_calculate_stress_grid(
+                RSlice::from_slice(&users),
+                &market_state,
+                RSlice::from_slice(&scenarios),
+                MarginRequirementType::Maintenance,
+                0,
+                false,
+                threads,
+                RSliceMut::from_mut_slice(&mut out),
+            );
+            assert!(result.is_ok());
+
+            let mut out = out.into_iter().map(|out| unsafe { out.assume_init() });
+            for user in &users {
+                for shocks in &scenarios {
+                    let expected = apply_price_shocks(&market_state, shocks).and_then(|state| {
+                        calculate_simplified_margin_requirement(
+                            user,
+                            &state,
+                            MarginRequirementType::Maintenance,
+                            0,
+                        )
+                    });
+                    match (expected, out.next().unwrap()) {
+                        (Ok(expected), RResult::ROk(actual)) => {
+                            assert_eq!(expected.total_collateral, actual.total_collateral.0);
+                            assert_eq!(expected.margin_requirement, actual.margin_requirement.0);
+                        }
+                        (Err(err), RResult::RErr(code)) => assert_eq!(u32::from(err), code),
+                        _ => panic!("grid result differs from single scenario calculation"),
+                    }
+                }
+            }
+        }
+
+        // a 30% drop costs the 1 SOL long $60 of collateral
+        let long_down = calculate_simplified_margin_requirement(
+            &long,
+            &apply_price_shocks(&market_state, &down).unwrap(),
+            MarginRequirementType::Maintenance,
+            0,
+        )
+        .unwrap();
+        assert_eq!(long_down.total_collateral, 40 * QUOTE_PRECISION_I128);
+
+        let mut out: Vec<_> = (1..users.len() * scenarios.len())
+            .map(|_| std::mem::MaybeUninit::uninit())
+            .collect();
+        let result = crate::exports::risk_calculate_stress_grid(
+            RSlice::from_slice(&users),
+            &market_state,
+            RSlice::from_slice(&scenarios),
+            MarginRequirementType::Maintenance,
+            0,
+            false,
+            0,
+            RSliceMut::from_mut_slice(&mut out),
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            u32::from(crate::types::FfiErrorCode::BufferTooSmall)
+        );
+    }
+}
@@ -1,18 +1,19 @@
 //! cross-boundary FFI types
 use std::collections::HashMap;
 
-use abi_stable::std_types::RResult;
+use abi_stable::std_types::{RResult, RSlice, RStr};
 use drift_program::{
     controller::position::PositionDirection,
+    error::{DriftResult, ErrorCode},
     math::{margin::MarginRequirementType, oracle::OracleValidity},
     state::{
         margin_calculation::MarginContext,
-        oracle::OraclePriceData,
+        oracle::{get_oracle_price, OraclePriceData, OracleSource},
         order_params::PostOnlyParam,
         perp_market::PerpMarket,
         spot_market::SpotMarket,
         state::OracleGuardRails,
-        user::{MarketType, OrderTriggerCondition, OrderType},
+        user::{MarketType, Order, OrderTriggerCondition, OrderType, User},
     },
 };
 use fxhash::FxBuildHasher;
@@ -24,7 +25,7 @@ use solana_sdk::{
 };
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct AccountWithKey {
     pub key: Pubkey,
     pub account: Account,
@@ -71,6 +72,10 @@ pub enum MarginContextMode {
     StandardMaintenance,
     StandardInitial,
     StandardCustom(MarginRequirementType),
+    /// standard context with strict oracle prices (bounded by the 5min TWAP) e.g. for withdrawals
+    StrictMaintenance,
+    StrictInitial,
+    StrictCustom(MarginRequirementType),
 }
 
 impl From<MarginContextMode> for MarginContext {
@@ -83,6 +88,13 @@ impl From<MarginContextMode> for MarginContext {
                 MarginContext::standard(MarginRequirementType::Initial)
             }
             MarginContextMode::StandardCustom(m) => MarginContext::standard(m),
+            MarginContextMode::StrictMaintenance => {
+                MarginContext::standard(MarginRequirementType::Maintenance).strict(true)
+            }
+            MarginContextMode::StrictInitial => {
+                MarginContext::standard(MarginRequirementType::Initial).strict(true)
+            }
+            MarginContextMode::StrictCustom(m) => MarginContext::standard(m).strict(true),
         }
     }
 }
@@ -123,6 +135,68 @@ pub struct IsolatedMarginCalculation {
     pub market_index: u16,
 }
 
+/// Outcome of a simulated perp order placement
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct PlacePerpOrderResult {
+    /// The user after placement, includes the new order, `open_orders` and `next_order_id`
+    pub user: User,
+    /// Index of the placed order in `user.orders`
+    pub order_index: usize,
+    /// The placed order with its final auction params
+    pub order: Order,
+}
+
+/// Largest token amounts a user can withdraw from a spot market
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaxWithdrawAmount {
+    /// Withdrawable from the user's deposit without borrowing
+    pub withdraw: u64,
+    /// Withdrawable when borrowing is allowed, at least `withdraw`
+    pub withdraw_with_borrow: u64,
+}
+
+/// Risk metrics of the cross account or of one isolated perp position
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RiskMetrics {
+    /// Maintenance total collateral
+    pub total_collateral: compat::i128,
+    /// Maintenance margin requirement
+    pub margin_requirement: compat::u128,
+    /// Collateral above the maintenance requirement in quote, negative once liquidatable
+    pub distance_to_liquidation: compat::i128,
+    /// Perp position notional plus spot liability value
+    pub total_notional: compat::u128,
+    /// `total_notional` over net asset value (`MARGIN_PRECISION`), `u128::MAX` if the net asset
+    /// value is not positive
+    pub leverage: compat::u128,
+    /// `total_collateral` over `total_notional` (`MARGIN_PRECISION`), `u128::MAX` without notional
+    pub margin_ratio: compat::u128,
+    /// 0-100, 0 when liquidatable and 100 without a margin requirement
+    pub health: u8,
+}
+
+/// Risk metrics of a user's cross account and isolated perp positions
+#[repr(C)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccountRiskMetrics {
+    pub cross: RiskMetrics,
+    /// Indexed like `User::perp_positions`, set for isolated positions only
+    pub isolated: [RiskMetrics; 8],
+}
+
+/// Relative change of one market's margin price
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PriceShock {
+    pub market_type: MarketType,
+    pub market_index: u16,
+    /// Price change (`PERCENTAGE_PRECISION`), e.g. `-100_000` for -10%
+    pub shock: i64,
+}
+
 impl MarginCalculation {
     pub fn get_free_collateral(&self) -> u128 {
         (self.total_collateral.0 - self.margin_requirement.0 as i128) // cast ok, margin_requirement > 0
@@ -216,6 +290,141 @@ pub struct MMOraclePriceData {
 /// C-ABI compatible result type for drift FFI calls
 pub type FfiResult<T> = RResult<T, u32>;
 
+/// Start of the `FfiResult` error code range reserved for failures of the FFI layer itself
+///
+/// Sits above the range used by drift program `ErrorCode`s (6000+)
+pub const FFI_ERROR_CODE_OFFSET: u32 = 9_000;
+
+/// Failures raised by the FFI layer rather than drift program logic
+///
+/// Missing markets, oracles, etc. are reported with the program's own `ErrorCode`s
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FfiErrorCode {
+    /// A panic was caught at the FFI boundary, message available via `ffi_last_panic_message`
+    Panic = FFI_ERROR_CODE_OFFSET,
+    /// Account data could not be decoded into the expected type
+    AccountDecode,
+    /// Account data does not start with the expected anchor discriminator
+    InvalidAccountDiscriminator,
+    /// Account data length does not match the account type size
+    InvalidAccountSize,
+    /// Account could not be encoded into account data
+    AccountEncode,
+    /// Output buffer is smaller than the data to be written
+    BufferTooSmall,
+}
+
+impl FfiErrorCode {
+    /// All FFI layer error codes
+    pub const ALL: &'static [FfiErrorCode] = &[
+        FfiErrorCode::Panic,
+        FfiErrorCode::AccountDecode,
+        FfiErrorCode::InvalidAccountDiscriminator,
+        FfiErrorCode::InvalidAccountSize,
+        FfiErrorCode::AccountEncode,
+        FfiErrorCode::BufferTooSmall,
+    ];
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::Panic => "Panic caught at the FFI boundary",
+            Self::AccountDecode => "Account data could not be decoded",
+            Self::InvalidAccountDiscriminator => "Account discriminator does not match",
+            Self::InvalidAccountSize => "Account data size does not match",
+            Self::AccountEncode => "Account could not be encoded",
+            Self::BufferTooSmall => "Output buffer too small",
+        }
+    }
+}
+
+impl From<FfiErrorCode> for u32 {
+    fn from(value: FfiErrorCode) -> Self {
+        value as u32
+    }
+}
+
+/// Error of an FFI call, either from drift program logic or the FFI layer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FfiError {
+    Program(ErrorCode),
+    Ffi(FfiErrorCode),
+}
+
+impl FfiError {
+    /// The `FfiResult` error code
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::Program(err) => (*err).into(),
+            Self::Ffi(err) => (*err).into(),
+        }
+    }
+
+    /// The error variant name e.g. `InsufficientCollateral`
+    pub fn name(&self) -> String {
+        match self {
+            Self::Program(err) => err.name(),
+            Self::Ffi(err) => format!("{err:?}"),
+        }
+    }
+
+    /// The human-readable error message
+    pub fn message(&self) -> String {
+        match self {
+            Self::Program(err) => err.to_string(),
+            Self::Ffi(err) => err.message().to_string(),
+        }
+    }
+}
+
+impl From<ErrorCode> for FfiError {
+    fn from(value: ErrorCode) -> Self {
+        Self::Program(value)
+    }
+}
+
+impl From<FfiErrorCode> for FfiError {
+    fn from(value: FfiErrorCode) -> Self {
+        Self::Ffi(value)
+    }
+}
+
+/// Build information of the library
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct FfiVersionInfo {
+    /// drift-ffi-sys crate version
+    pub crate_version: RStr<'static>,
+    /// drift program git tag the library is built from e.g. `v2.157.0`
+    pub program_tag: RStr<'static>,
+    /// drift program cluster the library is compiled for, `mainnet-beta` or `devnet`
+    pub cluster: RStr<'static>,
+    /// drift program ID the library is compiled for
+    pub program_id: Pubkey,
+    /// `rustc --version` of the compiler that built the library
+    pub rustc_version: RStr<'static>,
+    /// fingerprint of all exported signatures and type layouts, see `drift_ffi_abi.json`
+    pub layout_fingerprint: u64,
+}
+
+/// Size, alignment and key field offsets of a type as compiled into the library
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct FfiTypeLayout {
+    pub name: RStr<'static>,
+    pub size: usize,
+    pub align: usize,
+    pub fields: RSlice<'static, FfiFieldOffset>,
+}
+
+/// Byte offset of a field within its type
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct FfiFieldOffset {
+    pub name: RStr<'static>,
+    pub offset: usize,
+}
+
 pub mod compat {
     //! ffi compatible input types
 
@@ -242,8 +451,15 @@ pub mod compat {
     }
 }
 
+/// drift program cluster selected by crate feature
+#[cfg(feature = "mainnet-beta")]
+pub const CLUSTER: &str = "mainnet-beta";
+/// drift program cluster selected by crate feature
+#[cfg(feature = "devnet")]
+pub const CLUSTER: &str = "devnet";
+
 /// Simple HashMap-based implementation of market state
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct MarketState {
     pub spot_markets: HashMap<u16, SpotMarket, FxBuildHasher>,
     pub perp_markets: HashMap<u16, PerpMarket, FxBuildHasher>,
@@ -255,6 +471,37 @@ pub struct MarketState {
 }
 
 impl MarketState {
+    /// Build a `MarketState` from raw accounts
+    ///
+    /// Every perp and spot market is decoded and its oracle priced with the market's own
+    /// `oracle_source` as of `accounts.latest_slot`
+    pub fn from_accounts(accounts: &mut AccountsList) -> Result<Self, FfiError> {
+        let mut market_state = Self::default();
+        for account in accounts.spot_markets.iter() {
+            let market: SpotMarket = crate::accounts::decode_zero_copy(&account.account.data)?;
+            let price = oracle_price(
+                accounts.oracles,
+                market.oracle,
+                market.oracle_source,
+                accounts.latest_slot,
+            )?;
+            market_state.set_spot_oracle_price(market.market_index, price);
+            market_state.set_spot_market(market);
+        }
+        for account in accounts.perp_markets.iter() {
+            let market: PerpMarket = crate::accounts::decode_zero_copy(&account.account.data)?;
+            let price = oracle_price(
+                accounts.oracles,
+                market.amm.oracle,
+                market.amm.oracle_source,
+                accounts.latest_slot,
+            )?;
+            market_state.set_perp_oracle_price(market.market_index, price);
+            market_state.set_perp_market(market);
+        }
+        Ok(market_state)
+    }
+
     pub fn get_spot_market(&self, market_index: u16) -> &SpotMarket {
         self.spot_markets.get(&market_index).unwrap()
     }
@@ -263,6 +510,20 @@ impl MarketState {
         self.perp_markets.get(&market_index).unwrap()
     }
 
+    /// Like `get_spot_market` but returns `SpotMarketNotFound` for an unknown market
+    pub fn try_get_spot_market(&self, market_index: u16) -> DriftResult<&SpotMarket> {
+        self.spot_markets
+            .get(&market_index)
+            .ok_or(ErrorCode::SpotMarketNotFound)
+    }
+
+    /// Like `get_perp_market` but returns `PerpMarketNotFound` for an unknown market
+    pub fn try_get_perp_market(&self, market_index: u16) -> DriftResult<&PerpMarket> {
+        self.perp_markets
+            .get(&market_index)
+            .ok_or(ErrorCode::PerpMarketNotFound)
+    }
+
     pub fn get_spot_oracle_price(&self, market_index: u16) -> Option<&OraclePriceData> {
         self.spot_oracle_prices.get(&market_index)
     }
@@ -295,6 +556,80 @@ impl MarketState {
             })
     }
 
+    /// Spot price used for margin, see `select_oracle_price`
+    pub fn get_spot_margin_price(&self, market_index: u16) -> Option<OraclePriceData> {
+        self.get_spot_oracle_price(market_index)
+            .map(|oracle| self.select_oracle_price(oracle, self.get_spot_pyth_price(market_index)))
+    }
+
+    /// Perp price used for margin, see `select_oracle_price`
+    pub fn get_perp_margin_price(&self, market_index: u16) -> Option<OraclePriceData> {
+        self.get_perp_oracle_price(market_index)
+            .map(|oracle| self.select_oracle_price(oracle, self.get_perp_pyth_price(market_index)))
+    }
+
+    /// Price used for margin in the `market_type` market
+    pub fn get_margin_price(
+        &self,
+        market_type: MarketType,
+        market_index: u16,
+    ) -> Option<OraclePriceData> {
+        match market_type {
+            MarketType::Spot => self.get_spot_margin_price(market_index),
+            MarketType::Perp => self.get_perp_margin_price(market_index),
+        }
+    }
+
+    /// Set the price used for margin in the `market_type` market
+    ///
+    /// Replaces the oracle price (keeping its confidence, delay, etc.) and drops any pyth override
+    pub fn set_margin_price(&mut self, market_type: MarketType, market_index: u16, price: i64) {
+        let price_data = OraclePriceData {
+            price,
+            ..self
+                .get_margin_price(market_type, market_index)
+                .unwrap_or(OraclePriceData {
+                    price,
+                    confidence: 0,
+                    delay: 0,
+                    has_sufficient_number_of_data_points: true,
+                    sequence_id: None,
+                })
+        };
+        match market_type {
+            MarketType::Spot => {
+                self.spot_pyth_prices.remove(&market_index);
+                self.set_spot_oracle_price(market_index, price_data);
+            }
+            MarketType::Perp => {
+                self.perp_pyth_prices.remove(&market_index);
+                self.set_perp_oracle_price(market_index, price_data);
+            }
+        }
+    }
+
+    /// Prefer the `pyth` price over `oracle` when the oracle price is 0 or they differ by more than
+    /// `pyth_oracle_diff_threshold_bps`
+    fn select_oracle_price(
+        &self,
+        oracle: &OraclePriceData,
+        pyth: Option<OraclePriceData>,
+    ) -> OraclePriceData {
+        match pyth {
+            Some(p) if p.price != 0 && oracle.price == 0 => p,
+            Some(p) if p.price != 0 && oracle.price != 0 => {
+                let diff_bps =
+                    (p.price.abs_diff(oracle.price) * 10_000) / oracle.price.unsigned_abs();
+                if diff_bps > self.pyth_oracle_diff_threshold_bps {
+                    p
+                } else {
+                    *oracle
+                }
+            }
+            _ => *oracle,
+        }
+    }
+
     pub fn set_spot_market(&mut self, market: SpotMarket) {
         self.spot_markets.insert(market.market_index, market);
     }
@@ -319,3 +654,29 @@ impl MarketState {
         self.perp_pyth_prices.insert(market_index, price_data);
     }
 }
+
+/// Price `oracle` from its account in `oracles`, `QuoteAsset` oracles need no account
+fn oracle_price(
+    oracles: &mut [AccountWithKey],
+    oracle: Pubkey,
+    oracle_source: OracleSource,
+    slot: Slot,
+) -> Result<OraclePriceData, FfiError> {
+    let mut quote_asset;
+    let account = match oracles.iter_mut().find(|account| account.key == oracle) {
+        Some(account) => account,
+        None if oracle_source == OracleSource::QuoteAsset => {
+            quote_asset = AccountWithKey {
+                key: oracle,
+                account: Account::default(),
+            };
+            &mut quote_asset
+        }
+        None => return Err(ErrorCode::OracleNotFound.into()),
+    };
+    Ok(get_oracle_price(
+        &oracle_source,
+        &account.into_account_info(),
+        slot,
+    )?)
+}